@@ -0,0 +1,12 @@
+// Pinning a worker thread to a single core, through `core_affinity`. Without
+// the `affinity` feature it quietly reports that the thread wasn't pinned.
+
+#[cfg(feature = "affinity")]
+pub(crate) fn pin_current_thread(core: usize) -> bool {
+    core_affinity::set_for_current(core_affinity::CoreId { id: core })
+}
+
+#[cfg(not(feature = "affinity"))]
+pub(crate) fn pin_current_thread(_core: usize) -> bool {
+    false
+}
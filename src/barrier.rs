@@ -0,0 +1,88 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+// A barrier for `ThreadPool::barrier` that doesn't need to know up front how
+// many threads are coming. A `std::sync::Barrier` would, but we only find out
+// once every worker has been handed its job, and one that's on its way out
+// may never run it. So the count is only fixed once they've all been handed
+// out, and a job that's thrown away unrun takes itself off it.
+pub(crate) struct Rendezvous {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    arrived: usize,
+    // how many jobs are still going to arrive, counting the ones that have
+    expected: usize,
+    // set once every job is handed out, so `expected` won't grow any more
+    sealed: bool,
+}
+
+impl Rendezvous {
+    pub(crate) fn new() -> Arc<Rendezvous> {
+        Arc::new(Rendezvous {
+            state: Mutex::new(State {
+                arrived: 0,
+                expected: 0,
+                sealed: false,
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    // A job that waits for all the others. If it's dropped without running,
+    // the others stop waiting for it.
+    pub(crate) fn job(this: &Arc<Rendezvous>) -> impl FnOnce() + Send + 'static {
+        this.state.lock().unwrap().expected += 1;
+
+        let guard = Guard {
+            rendezvous: Arc::clone(this),
+            arrived: false,
+        };
+
+        move || {
+            let mut guard = guard;
+            guard.arrived = true;
+            guard.rendezvous.arrive();
+        }
+    }
+
+    // Every job has been handed out, so once they've all arrived, let them
+    // go.
+    pub(crate) fn seal(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sealed = true;
+        self.release_if_done(&state);
+    }
+
+    fn arrive(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.arrived += 1;
+        self.release_if_done(&state);
+
+        while !(state.sealed && state.arrived >= state.expected) {
+            state = self.released.wait(state).unwrap();
+        }
+    }
+
+    fn release_if_done(&self, state: &State) {
+        if state.sealed && state.arrived >= state.expected {
+            self.released.notify_all();
+        }
+    }
+}
+
+struct Guard {
+    rendezvous: Arc<Rendezvous>,
+    arrived: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if !self.arrived {
+            let mut state = self.rendezvous.state.lock().unwrap();
+            state.expected -= 1;
+            self.rendezvous.release_if_done(&state);
+        }
+    }
+}
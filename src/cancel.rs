@@ -0,0 +1,229 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+/// A flag a job can check to find out it's been asked to stop.
+///
+/// Cancellation is cooperative: setting the token doesn't interrupt the job
+/// in any way, it's up to the job to look at [`CancelToken::is_cancelled`]
+/// every so often and bail out, or to park on [`CancelToken::cancelled`]
+/// until it's told to. Clones share the same flag.
+///
+/// Tokens can be nested with [`CancelToken::child`]: cancelling a token
+/// cancels all of its children too, but not the other way round.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    node: Arc<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    cancelled: AtomicBool,
+    // Children that might still be around. Only looked at under the lock,
+    // which is also what a parked `wait` sleeps on.
+    children: Mutex<Vec<Weak<Node>>>,
+    woken: Condvar,
+}
+
+/// What [`CancelToken::cancelled`] hands back, to park on until the token
+/// is cancelled.
+#[derive(Debug)]
+#[must_use = "this does nothing unless you wait on it"]
+pub struct Cancelled<'a> {
+    token: &'a CancelToken,
+}
+
+impl CancelToken {
+    /// A fresh token that hasn't been cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// A new token that gets cancelled along with this one. Cancelling the
+    /// child leaves this one alone. If this token has already been
+    /// cancelled, so is the child.
+    ///
+    /// ```
+    /// use rustchat::CancelToken;
+    ///
+    /// let parent = CancelToken::new();
+    /// let child = parent.child();
+    ///
+    /// child.cancel();
+    /// assert!(!parent.is_cancelled());
+    ///
+    /// let child = parent.child();
+    /// parent.cancel();
+    /// assert!(child.is_cancelled());
+    /// ```
+    pub fn child(&self) -> CancelToken {
+        let child = CancelToken::new();
+        let mut children = self.node.children.lock().unwrap();
+
+        // Checked under the lock, so either we see the cancel here or it
+        // sees the child in the list.
+        if self.is_cancelled() {
+            child.node.cancelled.store(true, Ordering::Release);
+        } else {
+            // Don't let children that have come and gone pile up.
+            if children.len() == children.capacity() {
+                children.retain(|child| child.strong_count() > 0);
+            }
+            children.push(Arc::downgrade(&child.node));
+        }
+
+        drop(children);
+        child
+    }
+
+    /// Ask whoever holds this token, a clone of it, or one of its children
+    /// to stop. Anyone parked in [`Cancelled::wait`] wakes up.
+    pub fn cancel(&self) {
+        let mut pending = vec![Arc::clone(&self.node)];
+
+        // Done with a list rather than recursion, so a deep family of
+        // tokens can't run us out of stack.
+        while let Some(node) = pending.pop() {
+            if node.cancelled.swap(true, Ordering::AcqRel) {
+                continue;
+            }
+
+            let children = std::mem::take(&mut *node.children.lock().unwrap());
+            node.woken.notify_all();
+            pending.extend(children.iter().filter_map(Weak::upgrade));
+        }
+    }
+
+    /// Whether [`CancelToken::cancel`] has been called on this token, any
+    /// of its clones, or any of its parents.
+    pub fn is_cancelled(&self) -> bool {
+        self.node.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Something to park on until the token is cancelled, for a job that
+    /// has nothing to do but wait.
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use rustchat::{CancelToken, ThreadPool};
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// let token = CancelToken::new();
+    ///
+    /// let started = Instant::now();
+    /// pool.execute_with_token(&token, |token| token.cancelled().wait());
+    ///
+    /// std::thread::sleep(Duration::from_millis(50));
+    /// token.cancel();
+    /// pool.join().unwrap();
+    ///
+    /// assert!(started.elapsed() < Duration::from_secs(1));
+    /// ```
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+}
+
+impl Cancelled<'_> {
+    /// Block until the token is cancelled. Returns straight away if it
+    /// already has been.
+    pub fn wait(self) {
+        let node = &self.token.node;
+        let mut children = node.children.lock().unwrap();
+
+        while !self.token.is_cancelled() {
+            children = node.woken.wait(children).unwrap();
+        }
+    }
+
+    /// Block until the token is cancelled or `timeout` has passed, whichever
+    /// comes first. Returns whether it was cancelled.
+    pub fn wait_timeout(self, timeout: Duration) -> bool {
+        let node = &self.token.node;
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            self.wait();
+            return true;
+        };
+        let mut children = node.children.lock().unwrap();
+
+        while !self.token.is_cancelled() {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return false;
+            };
+            children = node.woken.wait_timeout(children, left).unwrap().0;
+        }
+
+        true
+    }
+}
+
+// The children are other tokens' business, so this just shows the flag.
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+// Cancels its token when it's dropped. Handed to the timer as a watchdog,
+// so the token still gets cancelled if the timer is closed, and its entry
+// thrown away, before it comes due.
+pub(crate) struct CancelOnDrop(pub(crate) CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// A handle to a job from [`ThreadPool::execute_cancelable`], which can stop
+/// it from running as long as no worker has picked it up yet.
+///
+/// Once the job has started, [`AbortHandle::abort`] does nothing; it runs to
+/// the end as normal. Dropping the handle doesn't abort the job.
+///
+/// [`ThreadPool::execute_cancelable`]: crate::ThreadPool::execute_cancelable
+#[derive(Debug, Clone)]
+pub struct AbortHandle {
+    aborted: Arc<AtomicBool>,
+}
+
+impl AbortHandle {
+    /// Wrap `f` so that it does nothing if the returned handle has been
+    /// aborted by the time it's called.
+    pub(crate) fn wrap<F>(f: F) -> (impl FnOnce() + Send + 'static, AbortHandle)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = AbortHandle {
+            aborted: Arc::new(AtomicBool::new(false)),
+        };
+        let aborted = Arc::clone(&handle.aborted);
+
+        let job = move || {
+            if !aborted.load(Ordering::Acquire) {
+                f();
+            }
+        };
+
+        (job, handle)
+    }
+
+    /// Skip the job if it's still waiting in the queue.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+
+    /// Whether [`AbortHandle::abort`] has been called on this handle or any
+    /// of its clones. That doesn't say whether the job had already started
+    /// by then.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use crate::{Distribution, QueueOrder, RejectionPolicy, ThreadPoolBuilder, ThreadPriority};
+
+/// A pool's settings as plain data, for pools that are configured from a
+/// file rather than in code.
+///
+/// Each field matches the [`ThreadPoolBuilder`] option of the same name,
+/// and a `None` leaves that option at its default. Only the options that
+/// are just values are here; hooks like a worker init closure or an
+/// observer still have to go through the builder, which a config can be
+/// turned into with `ThreadPoolBuilder::from`.
+///
+/// More options may turn up here later, so a config can't be written out
+/// as a struct literal; start from `PoolConfig::default()` and set the
+/// fields you want instead.
+///
+/// With the `serde` feature, a config can be serialized and deserialized.
+/// Any field that's left out gets its default, enum values are written in
+/// `snake_case`, and a field the config doesn't have is an error, so a typo
+/// doesn't go unnoticed.
+///
+/// ```
+/// use rustchat::{PoolConfig, QueueOrder, ThreadPool};
+///
+/// let mut config = PoolConfig::default();
+/// config.size = Some(2);
+/// config.thread_name_prefix = Some("loader".to_string());
+/// config.queue_order = QueueOrder::Lifo;
+///
+/// let pool = ThreadPool::from_config(config).unwrap();
+/// assert_eq!(pool.spawn(|| 6 * 7).join(), Ok(42));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+#[non_exhaustive]
+pub struct PoolConfig {
+    pub size: Option<usize>,
+    pub default_size: Option<usize>,
+    pub max_size: Option<usize>,
+    pub keep_alive: Option<Duration>,
+    pub thread_name_prefix: Option<String>,
+    pub stack_size: Option<usize>,
+    pub queue_capacity: Option<usize>,
+    pub rejection_policy: RejectionPolicy,
+    pub distribution: Distribution,
+    pub queue_order: QueueOrder,
+    pub pin_to_cores: bool,
+    pub thread_priority: ThreadPriority,
+}
+
+impl From<PoolConfig> for ThreadPoolBuilder {
+    fn from(config: PoolConfig) -> ThreadPoolBuilder {
+        let mut builder = ThreadPoolBuilder::new()
+            .rejection_policy(config.rejection_policy)
+            .distribution(config.distribution)
+            .queue_order(config.queue_order)
+            .pin_to_cores(config.pin_to_cores)
+            .thread_priority(config.thread_priority);
+
+        if let Some(size) = config.size {
+            builder = builder.size(size);
+        }
+        if let Some(size) = config.default_size {
+            builder = builder.default_size(size);
+        }
+        if let Some(max) = config.max_size {
+            builder = builder.max_size(max);
+        }
+        if let Some(keep_alive) = config.keep_alive {
+            builder = builder.keep_alive(keep_alive);
+        }
+        if let Some(prefix) = config.thread_name_prefix {
+            builder = builder.thread_name_prefix(prefix);
+        }
+        if let Some(bytes) = config.stack_size {
+            builder = builder.stack_size(bytes);
+        }
+        if let Some(capacity) = config.queue_capacity {
+            builder = builder.queue_capacity(capacity);
+        }
+
+        builder
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::PoolConfig;
+    use crate::{Distribution, QueueOrder, RejectionPolicy, ThreadPool, ThreadPriority};
+
+    #[test]
+    fn config_survives_a_json_round_trip() {
+        let config = PoolConfig {
+            size: Some(3),
+            keep_alive: Some(Duration::from_millis(1500)),
+            thread_name_prefix: Some("loader".to_owned()),
+            rejection_policy: RejectionPolicy::CallerRuns,
+            distribution: Distribution::RoundRobin,
+            queue_order: QueueOrder::Lifo,
+            thread_priority: ThreadPriority::Low,
+            ..PoolConfig::default()
+        };
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<PoolConfig>(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn pool_builds_from_a_small_json_config() {
+        let config: PoolConfig = serde_json::from_str(
+            r#"{
+                "size": 2,
+                "thread_name_prefix": "from-json",
+                "queue_capacity": 8,
+                "rejection_policy": "caller_runs",
+                "queue_order": "lifo"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.size, Some(2));
+        assert_eq!(config.rejection_policy, RejectionPolicy::CallerRuns);
+        assert_eq!(config.queue_order, QueueOrder::Lifo);
+        assert_eq!(config.distribution, Distribution::Shared);
+
+        let pool = ThreadPool::from_config(config).unwrap();
+        assert_eq!(pool.len(), 2);
+
+        let name = pool.spawn(thread_name).join().unwrap();
+        assert!(name.starts_with("from-json"), "{name}");
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let err = serde_json::from_str::<PoolConfig>(r#"{ "sise": 2 }"#).unwrap_err();
+        assert!(err.to_string().contains("sise"), "{err}");
+    }
+
+    fn thread_name() -> String {
+        std::thread::current().name().unwrap().to_owned()
+    }
+}
@@ -0,0 +1,116 @@
+use std::{fmt, sync::Arc};
+
+use crate::{JobHandle, ReentrantWait, ThreadPool};
+
+/// A [`ThreadPool`] whose jobs all get a reference to one shared context.
+///
+/// Jobs often need the same read-only state: a parsed config, a connection
+/// pool. Rather than have every closure capture its own handle to it, give
+/// it to the pool once and have jobs take `&Ctx` as an argument. Like
+/// `ThreadPool`, this is a cheap handle: clones share the same workers and
+/// the same context.
+///
+/// ```
+/// use rustchat::ContextPool;
+///
+/// struct Config {
+///     greeting: String,
+/// }
+///
+/// let pool = ContextPool::new(
+///     2,
+///     Config {
+///         greeting: "hello".to_string(),
+///     },
+/// );
+///
+/// let handle = pool.spawn(|config| format!("{}, world", config.greeting));
+/// assert_eq!(handle.join().unwrap(), "hello, world");
+/// ```
+pub struct ContextPool<Ctx> {
+    pool: ThreadPool,
+    context: Arc<Ctx>,
+}
+
+impl<Ctx> ContextPool<Ctx>
+where
+    Ctx: Send + Sync + 'static,
+{
+    /// Create a pool with `size` workers sharing `context`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::new`].
+    pub fn new(size: usize, context: Ctx) -> ContextPool<Ctx> {
+        ContextPool::with_pool(ThreadPool::new(size), context)
+    }
+
+    /// Share `context` with jobs run on an existing pool, for when it needs
+    /// options from [`ThreadPoolBuilder`](crate::ThreadPoolBuilder).
+    pub fn with_pool(pool: ThreadPool, context: Ctx) -> ContextPool<Ctx> {
+        ContextPool {
+            pool,
+            context: Arc::new(context),
+        }
+    }
+
+    /// Hand a job to the pool. It's called with the context once a worker
+    /// picks it up.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::execute`].
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce(&Ctx) + Send + 'static,
+    {
+        let context = Arc::clone(&self.context);
+        self.pool.execute(move || f(&context));
+    }
+
+    /// Run a job on the pool with the context and get a handle to its
+    /// return value, the same as [`ThreadPool::spawn`].
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(&Ctx) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let context = Arc::clone(&self.context);
+        self.pool.spawn(move || f(&context))
+    }
+
+    /// Block until every queued and running job has finished, the same as
+    /// [`ThreadPool::join`].
+    pub fn join(&self) -> Result<(), ReentrantWait> {
+        self.pool.join()
+    }
+
+    /// The context the jobs are given.
+    pub fn context(&self) -> &Ctx {
+        &self.context
+    }
+
+    /// The pool underneath, for everything else a `ThreadPool` can do.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+}
+
+// Derived, this would need `Ctx: Clone`, which it doesn't.
+impl<Ctx> Clone for ContextPool<Ctx> {
+    fn clone(&self) -> ContextPool<Ctx> {
+        ContextPool {
+            pool: self.pool.clone(),
+            context: Arc::clone(&self.context),
+        }
+    }
+}
+
+impl<Ctx: fmt::Debug> fmt::Debug for ContextPool<Ctx> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextPool")
+            .field("pool", &self.pool)
+            .field("context", &self.context)
+            .finish()
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fmt, sync::Arc};
+
+use crate::{ExecuteError, Priority, Shared, ThreadPool};
+
+/// Something that jobs can be handed to.
+///
+/// Code that only needs to get work run somewhere can take a
+/// `&dyn Executor` instead of a [`ThreadPool`], and tests can then pass it an
+/// [`InlineExecutor`] to keep everything on one thread.
+pub trait Executor {
+    /// Run `job`, now or later, on this thread or another.
+    fn execute(&self, job: Box<dyn FnOnce() + Send + 'static>);
+}
+
+impl Executor for ThreadPool {
+    /// Hand `job` to the pool, the same as [`ThreadPool::execute`].
+    fn execute(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        ThreadPool::execute(self, job);
+    }
+}
+
+/// A handle that can hand jobs to a pool and do nothing else, from
+/// [`ThreadPool::sender`].
+///
+/// Give this to code that should be able to submit work but not shut the
+/// pool down, resize it or look at its stats. It doesn't keep the pool
+/// going either: once the pool's last [`ThreadPool`] handle is dropped, the
+/// pool shuts down as usual and jobs sent from here are turned away with
+/// `ExecuteError::Shutdown`. Clones all send to the same pool.
+///
+/// ```
+/// use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+/// use rustchat::{JobSender, ThreadPool};
+///
+/// fn count_to(sender: &JobSender, total: &Arc<AtomicUsize>, n: usize) {
+///     for _ in 0..n {
+///         let total = Arc::clone(total);
+///         sender.execute(move || {
+///             total.fetch_add(1, Ordering::Relaxed);
+///         });
+///     }
+/// }
+///
+/// let pool = ThreadPool::new(4);
+/// let total = Arc::new(AtomicUsize::new(0));
+///
+/// count_to(&pool.sender(), &total, 10);
+///
+/// pool.join().unwrap();
+/// assert_eq!(total.load(Ordering::Relaxed), 10);
+/// ```
+#[derive(Clone)]
+pub struct JobSender {
+    pool: Arc<Shared>,
+}
+
+impl JobSender {
+    pub(crate) fn new(pool: Arc<Shared>) -> JobSender {
+        JobSender { pool }
+    }
+
+    /// Hand a job to the pool, the same as [`ThreadPool::execute`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, including once it's shut
+    /// down. See [`JobSender::try_execute`] for a version that gives the
+    /// closure back instead.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute(f).unwrap();
+    }
+
+    /// Hand a job to the pool, or get it back if nobody can run it, the
+    /// same as [`ThreadPool::try_execute`].
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.execute(Priority::Normal, f)
+    }
+}
+
+impl Executor for JobSender {
+    /// Hand `job` to the pool, the same as [`JobSender::execute`].
+    fn execute(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        JobSender::execute(self, job);
+    }
+}
+
+// There's nothing to show but which pool it sends to.
+impl fmt::Debug for JobSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobSender")
+            .field("pool", &self.pool.id)
+            .finish()
+    }
+}
+
+/// An [`Executor`] that runs every job straight away on the calling thread.
+///
+/// It doesn't spawn any threads, so code under test runs deterministically
+/// and a job has always finished by the time `execute` returns. Besides the
+/// trait, it has the same `execute` and `try_execute` methods as
+/// [`ThreadPool`], so it can stand in for a pool without going through
+/// `dyn Executor`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InlineExecutor;
+
+impl InlineExecutor {
+    /// Create an inline executor. It has no state, so this is the same as
+    /// `InlineExecutor` or `InlineExecutor::default()`.
+    pub fn new() -> InlineExecutor {
+        InlineExecutor
+    }
+
+    /// Run a job right here, before returning.
+    ///
+    /// If the job panics, the panic carries on up through this call.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        f();
+    }
+
+    /// Run a job right here, before returning. This never fails, it's
+    /// only here to match [`ThreadPool::try_execute`].
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        f();
+        Ok(())
+    }
+}
+
+impl Executor for InlineExecutor {
+    fn execute(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        job();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+    };
+
+    use super::{Executor, InlineExecutor};
+    use crate::ThreadPool;
+
+    fn count_to(executor: &dyn Executor, total: &Arc<AtomicUsize>, n: usize) {
+        for _ in 0..n {
+            let total = Arc::clone(total);
+            executor.execute(Box::new(move || {
+                total.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+    }
+
+    #[test]
+    fn pool_and_inline_executor_both_run_the_jobs() {
+        let pool = ThreadPool::new(2);
+        let total = Arc::new(AtomicUsize::new(0));
+        count_to(&pool, &total, 10);
+        pool.join().unwrap();
+        assert_eq!(total.load(Ordering::Relaxed), 10);
+
+        let total = Arc::new(AtomicUsize::new(0));
+        count_to(&InlineExecutor, &total, 10);
+        assert_eq!(total.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn inline_executor_runs_the_job_before_returning() {
+        let executor = InlineExecutor::new();
+        let (tx, rx) = mpsc::channel();
+
+        let caller = thread::current().id();
+        executor.execute(move || tx.send(thread::current().id()).unwrap());
+        assert_eq!(rx.try_recv(), Ok(caller));
+
+        let (tx, rx) = mpsc::channel();
+        executor.try_execute(move || tx.send(()).unwrap()).unwrap();
+        assert_eq!(rx.try_recv(), Ok(()));
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use crate::{queue::PushMode, Job, Priority, Shared, ThreadPool};
+
+/// A set of jobs where some can't start until others have finished.
+///
+/// Add jobs with [`TaskGraph::add`], say which ones have to wait for which
+/// with [`TaskGraph::depends_on`], then [`TaskGraph::run`] the lot on a
+/// pool. Each job is handed to the pool as soon as everything it depends on
+/// has finished, so independent jobs still run side by side.
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use rustchat::{TaskGraph, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let order = Arc::new(Mutex::new(Vec::new()));
+/// let mut graph = TaskGraph::new();
+///
+/// let log = |name| {
+///     let order = Arc::clone(&order);
+///     move || order.lock().unwrap().push(name)
+/// };
+///
+/// let fetch = graph.add(log("fetch"));
+/// let build = graph.add(log("build"));
+/// graph.depends_on(build, fetch);
+///
+/// graph.run(&pool).unwrap();
+/// assert_eq!(*order.lock().unwrap(), ["fetch", "build"]);
+/// ```
+#[derive(Default)]
+pub struct TaskGraph {
+    tasks: Vec<Job>,
+    // for each task, the tasks waiting on it
+    dependents: Vec<Vec<usize>>,
+    // for each task, how many tasks it's waiting on
+    waiting_on: Vec<usize>,
+}
+
+/// Identifies a job in a [`TaskGraph`], handed back by [`TaskGraph::add`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(usize);
+
+/// Why [`TaskGraph::run`] didn't run every job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// The dependencies go round in a circle, so there's no order to run
+    /// them in. Nothing was run.
+    Cycle,
+    /// A job panicked. Everything else ran, apart from the jobs that
+    /// depended on it, directly or not.
+    Panic,
+    /// The pool shut down before some of the jobs could run. Like a panic,
+    /// that leaves out the jobs that depended on them too.
+    Canceled,
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::Cycle => f.write_str("task graph has a dependency cycle"),
+            GraphError::Panic => f.write_str("a task in the graph panicked"),
+            GraphError::Canceled => f.write_str("the pool shut down before the graph finished"),
+        }
+    }
+}
+
+impl Error for GraphError {}
+
+impl TaskGraph {
+    /// An empty graph.
+    pub fn new() -> TaskGraph {
+        TaskGraph::default()
+    }
+
+    /// Add a job to the graph. Until it's given dependencies, it can run as
+    /// soon as the graph starts.
+    pub fn add<F>(&mut self, f: F) -> TaskId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tasks.push(Box::new(f));
+        self.dependents.push(Vec::new());
+        self.waiting_on.push(0);
+        TaskId(self.tasks.len() - 1)
+    }
+
+    /// Make `task` wait until `dependency` has finished before it starts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either id didn't come from this graph.
+    pub fn depends_on(&mut self, task: TaskId, dependency: TaskId) {
+        assert!(
+            task.0 < self.tasks.len() && dependency.0 < self.tasks.len(),
+            "task id from a different graph"
+        );
+
+        self.dependents[dependency.0].push(task.0);
+        self.waiting_on[task.0] += 1;
+    }
+
+    /// Run every job on `pool`, in an order that respects the
+    /// dependencies, and block until they've all finished.
+    ///
+    /// If a job panics, the panic is reported like any other job's, and
+    /// everything that depends on it is skipped. Calling this from inside a
+    /// job can deadlock, the same as [`ThreadPool::join`].
+    pub fn run(self, pool: &ThreadPool) -> Result<(), GraphError> {
+        if self.has_cycle() {
+            return Err(GraphError::Cycle);
+        }
+
+        let TaskGraph {
+            tasks,
+            dependents,
+            waiting_on,
+        } = self;
+
+        let run = Arc::new(Run {
+            pool: Arc::clone(&pool.inner.shared),
+            pending: Mutex::new(tasks.len()),
+            nodes: tasks
+                .into_iter()
+                .zip(dependents)
+                .zip(&waiting_on)
+                .map(|((task, dependents), &waiting_on)| Node {
+                    task: Mutex::new(Some(task)),
+                    waiting_on: AtomicUsize::new(waiting_on),
+                    skip: AtomicBool::new(false),
+                    settled: AtomicBool::new(false),
+                    dependents,
+                })
+                .collect(),
+            done: Condvar::new(),
+            panicked: AtomicBool::new(false),
+            canceled: AtomicBool::new(false),
+        });
+
+        // Start the tasks that aren't waiting on anything. Every other task
+        // gets started by whichever of its dependencies finishes last.
+        for (id, &waiting_on) in waiting_on.iter().enumerate() {
+            if waiting_on == 0 && !Run::start(&run, id, PushMode::Wait) {
+                Run::finish(&run, id, false);
+            }
+        }
+
+        let mut pending = run.pending.lock().unwrap();
+
+        while *pending > 0 {
+            pending = run.done.wait(pending).unwrap();
+        }
+
+        if run.panicked.load(Ordering::Acquire) {
+            Err(GraphError::Panic)
+        } else if run.canceled.load(Ordering::Acquire) {
+            Err(GraphError::Canceled)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Kahn's algorithm: keep taking away tasks that aren't waiting on
+    // anything. If that doesn't get through all of them, the rest are
+    // waiting on each other.
+    fn has_cycle(&self) -> bool {
+        let mut waiting_on = self.waiting_on.clone();
+        let mut ready: VecDeque<usize> = (0..waiting_on.len())
+            .filter(|&id| waiting_on[id] == 0)
+            .collect();
+        let mut seen = 0;
+
+        while let Some(id) = ready.pop_front() {
+            seen += 1;
+
+            for &dependent in &self.dependents[id] {
+                waiting_on[dependent] -= 1;
+
+                if waiting_on[dependent] == 0 {
+                    ready.push_back(dependent);
+                }
+            }
+        }
+
+        seen < self.tasks.len()
+    }
+}
+
+// The jobs can't be printed, so we just say how many there are.
+impl fmt::Debug for TaskGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskGraph")
+            .field("tasks", &self.tasks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// A graph that's been handed to the pool.
+struct Run {
+    pool: Arc<Shared>,
+    nodes: Vec<Node>,
+    // tasks that haven't finished (or been skipped) yet
+    pending: Mutex<usize>,
+    done: Condvar,
+    panicked: AtomicBool,
+    canceled: AtomicBool,
+}
+
+struct Node {
+    // taken out when the task is started
+    task: Mutex<Option<Job>>,
+    // dependencies that haven't finished yet
+    waiting_on: AtomicUsize,
+    // set if one of the dependencies didn't run to the end
+    skip: AtomicBool,
+    // set once the task's outcome is known, so its `TaskGuard` leaves it be
+    settled: AtomicBool,
+    dependents: Vec<usize>,
+}
+
+impl Run {
+    // Hand task `id` to the pool, now that it isn't waiting on anything.
+    // Gives back false if it didn't go, because it's being skipped or the
+    // pool is shutting down, in which case it's up to the caller to finish
+    // it.
+    fn start(this: &Arc<Run>, id: usize, mode: PushMode) -> bool {
+        let node = &this.nodes[id];
+
+        if node.skip.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let task = node.task.lock().unwrap().take();
+        let guard = TaskGuard {
+            run: Arc::clone(this),
+            id,
+        };
+
+        let job = move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| task.map(|task| task())));
+            guard.run.nodes[guard.id]
+                .settled
+                .store(true, Ordering::Release);
+
+            // Let the dependents go before passing the panic on to the
+            // worker, so it gets reported like any other.
+            if let Err(payload) = result {
+                guard.run.panicked.store(true, Ordering::Release);
+                Run::finish(&guard.run, guard.id, false);
+                panic::resume_unwind(payload);
+            }
+
+            Run::finish(&guard.run, guard.id, true);
+        };
+
+        // This only fails once the pool is shutting down. The job comes
+        // back then, and it's settled before it's dropped so that its guard
+        // doesn't finish it from in here.
+        match this.pool.submit(Priority::Normal, job, mode) {
+            Ok(()) => true,
+            Err(e) => {
+                node.settled.store(true, Ordering::Release);
+                this.canceled.store(true, Ordering::Release);
+                drop(e);
+                false
+            }
+        }
+    }
+
+    // Count task `id` as done, and start whatever was only waiting on it.
+    // If it didn't run to the end, they're skipped instead. Skipped tasks
+    // are finished in turn, off a list rather than by calling back in here,
+    // so a long chain of them can't run out of stack.
+    fn finish(this: &Arc<Run>, id: usize, ok: bool) {
+        let mut finished = vec![(id, ok)];
+
+        while let Some((id, ok)) = finished.pop() {
+            for &dependent in &this.nodes[id].dependents {
+                let node = &this.nodes[dependent];
+
+                if !ok {
+                    node.skip.store(true, Ordering::Release);
+                }
+
+                // We're usually on a worker here, so this mustn't wait for
+                // room in the queue.
+                if node.waiting_on.fetch_sub(1, Ordering::AcqRel) == 1
+                    && !Run::start(this, dependent, PushMode::Force)
+                {
+                    finished.push((dependent, false));
+                }
+            }
+
+            // Only once the dependents are accounted for, so the count can't
+            // reach zero while some of them still have to start.
+            let mut pending = this.pending.lock().unwrap();
+            *pending -= 1;
+
+            if *pending == 0 {
+                this.done.notify_all();
+            }
+        }
+    }
+}
+
+// Owned by a task's job. If the job is dropped without being run, this
+// counts the task as canceled, so `TaskGraph::run` isn't left waiting.
+struct TaskGuard {
+    run: Arc<Run>,
+    id: usize,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if !self.run.nodes[self.id].settled.swap(true, Ordering::AcqRel) {
+            self.run.canceled.store(true, Ordering::Release);
+            Run::finish(&self.run, self.id, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use super::{GraphError, TaskGraph};
+    use crate::ThreadPool;
+
+    #[test]
+    fn diamond_runs_the_join_after_both_branches() {
+        let pool = ThreadPool::new(4);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut graph = TaskGraph::new();
+
+        let log = |name| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+
+        let top = graph.add(log("top"));
+        let left = graph.add(log("left"));
+        let right = graph.add(log("right"));
+        let bottom = graph.add(log("bottom"));
+        graph.depends_on(left, top);
+        graph.depends_on(right, top);
+        graph.depends_on(bottom, left);
+        graph.depends_on(bottom, right);
+
+        graph.run(&pool).unwrap();
+
+        let order = order.lock().unwrap();
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[0], "top");
+        assert_eq!(order[3], "bottom");
+    }
+
+    #[test]
+    fn cycle_is_refused_without_running_anything() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let mut graph = TaskGraph::new();
+
+        let ids: Vec<_> = (0..3)
+            .map(|_| {
+                let ran = Arc::clone(&ran);
+                graph.add(move || {
+                    ran.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        graph.depends_on(ids[1], ids[0]);
+        graph.depends_on(ids[2], ids[1]);
+        graph.depends_on(ids[0], ids[2]);
+
+        assert_eq!(graph.run(&pool), Err(GraphError::Cycle));
+        pool.join().unwrap();
+        assert_eq!(ran.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn skipping_a_long_chain_does_not_overflow_the_stack() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let mut graph = TaskGraph::new();
+
+        let mut previous = graph.add(|| panic!("first link"));
+
+        for _ in 0..100_000 {
+            let ran = Arc::clone(&ran);
+            let next = graph.add(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            });
+            graph.depends_on(next, previous);
+            previous = next;
+        }
+
+        assert_eq!(graph.run(&pool), Err(GraphError::Panic));
+        assert_eq!(ran.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+};
+
+use crate::{panic_message, queue::PushMode, Priority, Shared};
+
+/// Why a spawned job didn't produce a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked while it was running, with this message. Panics
+    /// that weren't given a string just say `Box<dyn Any>`.
+    Panic(String),
+    /// The job was thrown away before a worker got to run it.
+    Canceled,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panic(message) => write!(f, "job panicked: {message}"),
+            JobError::Canceled => f.write_str("job was canceled before it ran"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// A handle to the result of a job started with
+/// [`ThreadPool::spawn`](crate::ThreadPool::spawn).
+pub struct JobHandle<T> {
+    packet: Arc<Packet<T>>,
+    // the pool the job went to, so continuations can go there too
+    pool: Arc<Shared>,
+}
+
+impl<T> JobHandle<T> {
+    /// Block until the job has finished and take its return value.
+    pub fn join(self) -> Result<T, JobError> {
+        let mut slot = self.packet.slot.lock().unwrap();
+
+        loop {
+            match slot.result.take() {
+                Some(result) => return result,
+                None => slot = self.packet.done.wait(slot).unwrap(),
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> JobHandle<T> {
+    /// Run `g` on the pool with this job's return value, once it's
+    /// finished, and get a handle to what `g` returns.
+    ///
+    /// `g` is handed to the pool as a job of its own when this one
+    /// finishes, so nothing sits blocked waiting for it in the meantime.
+    /// If this job panics or is thrown away, `g` never runs, and the new
+    /// handle reports the same error.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let answer = pool.spawn(|| 21).then(|x| x * 2);
+    ///
+    /// assert_eq!(answer.join(), Ok(42));
+    /// ```
+    pub fn then<U, G>(self, g: G) -> JobHandle<U>
+    where
+        G: FnOnce(T) -> U + Send + 'static,
+        U: Send + 'static,
+    {
+        let (completer, handle) = packet(&self.pool);
+        let pool = Arc::clone(&self.pool);
+
+        let continuation = move |result: Result<T, JobError>| match result {
+            Ok(value) => {
+                // We're usually on a worker here, so this mustn't wait for
+                // room in the queue. It only fails once the pool is
+                // shutting down, and then dropping the job reports
+                // `JobError::Canceled`.
+                let job = run(completer, move || g(value));
+                let _ = pool.submit(Priority::Normal, job, PushMode::Force);
+            }
+            Err(e) => completer.complete(Err(e)),
+        };
+
+        self.on_complete(continuation);
+        handle
+    }
+
+    // Hand the job's result to `f` once it's in, rather than keeping it for
+    // `join`. If it's already in, `f` is called right here.
+    pub(crate) fn on_complete<F>(self, f: F)
+    where
+        F: FnOnce(Result<T, JobError>) + Send + 'static,
+    {
+        let mut slot = self.packet.slot.lock().unwrap();
+
+        match slot.result.take() {
+            Some(result) => {
+                drop(slot);
+                f(result);
+            }
+            None => slot.then = Some(Box::new(f)),
+        }
+    }
+}
+
+/// The results of a batch of jobs started with
+/// [`ThreadPool::spawn_all`](crate::ThreadPool::spawn_all).
+///
+/// Take them all at once, in the order the jobs were handed over, with
+/// [`JobSet::join_all`], or one at a time as the jobs finish with
+/// [`JobSet::join_next`]. A `JobSet` is also an iterator over the latter.
+/// Dropping it doesn't stop the jobs; their results are just thrown away.
+pub struct JobSet<T> {
+    results: mpsc::Receiver<(usize, Result<T, JobError>)>,
+    // jobs whose results haven't been taken yet
+    remaining: usize,
+}
+
+impl<T: Send + 'static> JobSet<T> {
+    pub(crate) fn new(handles: Vec<JobHandle<T>>) -> JobSet<T> {
+        let (tx, rx) = mpsc::channel();
+        let remaining = handles.len();
+
+        for (index, handle) in handles.into_iter().enumerate() {
+            let tx = tx.clone();
+
+            // The set may have been dropped by now, which is fine.
+            handle.on_complete(move |result| {
+                let _ = tx.send((index, result));
+            });
+        }
+
+        JobSet {
+            results: rx,
+            remaining,
+        }
+    }
+}
+
+impl<T> JobSet<T> {
+    /// Block until every job has finished, and take their results in the
+    /// order the jobs were handed over. Results already taken with
+    /// [`JobSet::join_next`] are left out.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let set = pool.spawn_all((0..5).map(|i| move || i * 10));
+    ///
+    /// let results: Vec<_> = set.join_all().into_iter().map(Result::unwrap).collect();
+    /// assert_eq!(results, [0, 10, 20, 30, 40]);
+    /// ```
+    pub fn join_all(mut self) -> Vec<Result<T, JobError>> {
+        let mut results = Vec::with_capacity(self.remaining);
+
+        while let Some(result) = self.recv() {
+            results.push(result);
+        }
+
+        results.sort_by_key(|&(index, _)| index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Block until the next job finishes, whichever one that is, and take
+    /// its result. Gives back `None` once every result has been taken.
+    ///
+    /// ```
+    /// use std::{thread, time::Duration};
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let mut set = pool.spawn_all([200, 0].map(|ms| {
+    ///     move || {
+    ///         thread::sleep(Duration::from_millis(ms));
+    ///         ms
+    ///     }
+    /// }));
+    ///
+    /// assert_eq!(set.join_next(), Some(Ok(0)));
+    /// assert_eq!(set.join_next(), Some(Ok(200)));
+    /// assert_eq!(set.join_next(), None);
+    /// ```
+    pub fn join_next(&mut self) -> Option<Result<T, JobError>> {
+        self.recv().map(|(_, result)| result)
+    }
+
+    /// How many results haven't been taken yet.
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    /// Whether every result has been taken.
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn recv(&mut self) -> Option<(usize, Result<T, JobError>)> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        // Every job reports back, even one that's thrown away unrun, so
+        // this can't run dry before `remaining` does.
+        let result = self.results.recv().ok()?;
+        self.remaining -= 1;
+        Some(result)
+    }
+}
+
+impl<T> Iterator for JobSet<T> {
+    type Item = Result<T, JobError>;
+
+    fn next(&mut self) -> Option<Result<T, JobError>> {
+        self.join_next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> ExactSizeIterator for JobSet<T> {}
+
+impl<T> fmt::Debug for JobSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobSet")
+            .field("remaining", &self.remaining)
+            .finish_non_exhaustive()
+    }
+}
+
+// Where the job leaves its result for the handle to pick up.
+struct Packet<T> {
+    slot: Mutex<Slot<T>>,
+    done: Condvar,
+}
+
+struct Slot<T> {
+    result: Option<Result<T, JobError>>,
+    // what to do with the result instead of keeping it, set by `then`
+    then: Option<Continuation<T>>,
+}
+
+type Continuation<T> = Box<dyn FnOnce(Result<T, JobError>) + Send>;
+
+impl<T> Packet<T> {
+    fn complete(&self, value: Result<T, JobError>) {
+        let mut slot = self.slot.lock().unwrap();
+
+        match slot.then.take() {
+            Some(then) => {
+                drop(slot);
+                then(value);
+            }
+            None => {
+                slot.result = Some(value);
+                self.done.notify_all();
+            }
+        }
+    }
+}
+
+// Owned by the job closure. If the closure is dropped without ever being
+// called, the handle would otherwise wait forever, so we fill in
+// `JobError::Canceled` on the way out.
+struct Completer<T> {
+    packet: Option<Arc<Packet<T>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(mut self, value: Result<T, JobError>) {
+        if let Some(packet) = self.packet.take() {
+            packet.complete(value);
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if let Some(packet) = self.packet.take() {
+            packet.complete(Err(JobError::Canceled));
+        }
+    }
+}
+
+// Wrap `f` up as a plain job for `pool` that reports its result to the
+// returned handle.
+pub(crate) fn job_with_handle<F, T>(
+    pool: &Arc<Shared>,
+    f: F,
+) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (completer, handle) = packet(pool);
+    (run(completer, f), handle)
+}
+
+// A fresh packet, with one end for the job and the other for the caller.
+fn packet<T>(pool: &Arc<Shared>) -> (Completer<T>, JobHandle<T>) {
+    let packet = Arc::new(Packet {
+        slot: Mutex::new(Slot {
+            result: None,
+            then: None,
+        }),
+        done: Condvar::new(),
+    });
+
+    let completer = Completer {
+        packet: Some(Arc::clone(&packet)),
+    };
+
+    let handle = JobHandle {
+        packet,
+        pool: Arc::clone(pool),
+    };
+
+    (completer, handle)
+}
+
+// Run `f`, handing what it returns to `completer`.
+fn run<F, T>(completer: Completer<T>, f: F) -> impl FnOnce() + Send + 'static
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // We catch the panic here so the handle can tell a panic apart from a
+    // job that never ran, then let it carry on up to the worker so it gets
+    // reported like any other panicking job.
+    move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => completer.complete(Ok(value)),
+        Err(payload) => {
+            let message = panic_message(payload.as_ref()).to_string();
+            completer.complete(Err(JobError::Panic(message)));
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobError;
+    use crate::ThreadPool;
+
+    #[test]
+    fn join_gives_back_the_return_value() {
+        let pool = ThreadPool::new(2);
+        assert_eq!(pool.spawn(|| 40 + 2).join(), Ok(42));
+    }
+
+    #[test]
+    fn join_reports_a_panic() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_handler(|_| {});
+
+        let result = pool.spawn(|| -> i32 { panic!("boom") }).join();
+        assert!(matches!(result, Err(JobError::Panic(_))));
+    }
+
+    #[test]
+    fn panic_message_comes_back_with_the_error() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_handler(|_| {});
+
+        // Both kinds of payload: a `&str` literal and a formatted `String`.
+        let literal = pool.spawn(|| panic!("boom")).join().unwrap_err();
+        assert_eq!(literal, JobError::Panic("boom".to_owned()));
+
+        let code = 7;
+        let formatted = pool
+            .spawn(move || panic!("boom {code}"))
+            .join()
+            .unwrap_err();
+        assert!(formatted.to_string().contains("boom 7"), "{formatted}");
+    }
+}
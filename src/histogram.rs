@@ -0,0 +1,105 @@
+use std::{
+    array,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Each power of two (in microseconds) is split into this many buckets, so a
+// reported value is never more than a quarter off the real one.
+const SUB_BUCKETS: u64 = 4;
+
+// Enough buckets to cover every `u64` number of microseconds.
+const BUCKETS: usize = 64 * SUB_BUCKETS as usize;
+
+// A histogram of durations that only keeps a count per bucket, so it stays
+// the same size however many samples go in. Recording is a single atomic
+// add, so workers never wait on each other for it.
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[index(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The duration that `quantile` of the samples are at or below, rounded
+    // up to the top of its bucket. Zero if nothing's been recorded.
+    pub(crate) fn quantile(&self, quantile: f64) -> Duration {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+
+        let total: u64 = counts.iter().sum();
+
+        if total == 0 {
+            return Duration::ZERO;
+        }
+
+        let target = ((quantile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+
+        for (index, count) in counts.into_iter().enumerate() {
+            seen += count;
+
+            if seen >= target {
+                return Duration::from_micros(upper_bound(index));
+            }
+        }
+
+        Duration::MAX
+    }
+}
+
+// Values below `SUB_BUCKETS` get a bucket each. Above that, the top bit picks
+// the power of two and the two bits after it pick the bucket within it.
+fn index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+
+    let exp = u64::from(63 - micros.leading_zeros());
+    let sub = (micros >> (exp - 2)) & (SUB_BUCKETS - 1);
+    ((exp - 1) * SUB_BUCKETS + sub) as usize
+}
+
+// The largest value that lands in bucket `index`.
+fn upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let exp = index / SUB_BUCKETS + 1;
+    let sub = index % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub) << (exp - 2);
+    lower + ((1 << (exp - 2)) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{index, upper_bound};
+
+    #[test]
+    fn buckets_cover_their_values_to_within_a_quarter() {
+        for micros in [0, 3, 4, 7, 8, u64::MAX] {
+            let bound = upper_bound(index(micros));
+
+            assert!(bound >= micros, "{micros} reported as {bound}");
+            assert!(bound - micros <= micros / 4, "{micros} reported as {bound}");
+
+            // The top of a bucket is still in that bucket.
+            assert_eq!(index(bound), index(micros));
+        }
+    }
+}
@@ -1,123 +1,3261 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
-    thread, usize,
+    any::Any,
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt, io, mem,
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+#[macro_use]
+mod log;
+
+mod affinity;
+mod barrier;
+mod cancel;
+mod config;
+mod context;
+mod executor;
+mod graph;
+mod handle;
+mod histogram;
+mod local;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod observer;
+mod priority;
+mod queue;
+mod scope;
+#[cfg(feature = "futures")]
+mod task;
+#[cfg(test)]
+mod tests;
+mod timer;
+mod typed;
+mod wait_group;
+
+pub use cancel::{AbortHandle, CancelToken, Cancelled};
+pub use config::PoolConfig;
+pub use context::ContextPool;
+pub use executor::{Executor, InlineExecutor, JobSender};
+pub use graph::{GraphError, TaskGraph, TaskId};
+pub use handle::{JobError, JobHandle, JobSet};
+pub use local::{LocalPool, LocalSpawner};
+pub use observer::PoolObserver;
+pub use priority::ThreadPriority;
+pub use queue::{Distribution, Priority, QueueOrder, RejectionPolicy};
+pub use scope::Scope;
+pub use timer::TaskHandle;
+pub use typed::TypedThreadPool;
+pub use wait_group::{WaitGroup, WaitGroupGuard};
+
+use barrier::Rendezvous;
+use cancel::CancelOnDrop;
+use histogram::Histogram;
+use queue::{Message, PushError, PushMode, Queue, Queued};
+use timer::{Action, Timer};
+
+/// A pool of worker threads that jobs can be handed to.
+///
+/// `ThreadPool` is a cheap handle: cloning it gives you another handle to
+/// the same workers, and any of them can submit jobs. The workers are only
+/// shut down once the last handle is dropped, or when one of them calls
+/// [`ThreadPool::shutdown`].
+#[derive(Clone)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    inner: Arc<Inner>,
+}
+
+/// A handle to a [`ThreadPool`] that doesn't keep it alive, made with
+/// [`ThreadPool::downgrade`].
+///
+/// This is to `ThreadPool` what `Weak` is to `Arc`: once every
+/// `ThreadPool` handle is gone and the pool has shut down,
+/// [`WeakThreadPool::upgrade`] gives back `None`.
+#[derive(Clone)]
+pub struct WeakThreadPool {
+    inner: Weak<Inner>,
+}
+
+impl WeakThreadPool {
+    /// Get a `ThreadPool` handle back, if there's still a pool to get.
+    pub fn upgrade(&self) -> Option<ThreadPool> {
+        self.inner.upgrade().map(|inner| ThreadPool { inner })
+    }
+}
+
+impl fmt::Debug for WeakThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakThreadPool").finish_non_exhaustive()
+    }
+}
+
+// There's exactly one of these per pool, owned by the handles. Workers only
+// ever see `Shared`, so when the last handle goes away this gets dropped and
+// takes the workers down with it.
+struct Inner {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.shared.shutdown();
+    }
+}
+
+// Everything the workers and the pool both need to get at.
+struct Shared {
+    // see `ThreadPool::id`
+    id: u64,
+    // ourselves, for spawning workers from places that only have a `&Shared`
+    me: Weak<Shared>,
+    // the handles' side, for `ThreadPool::current`. Set once, as soon as
+    // there is one, which is before any workers are spawned.
+    handle: OnceLock<Weak<Inner>>,
+    queue: Queue,
+    rejection: RejectionPolicy,
+    distribution: Distribution,
+    // bumped for every job under `Distribution::RoundRobin`, to pick whose
+    // turn it is
+    next_turn: AtomicUsize,
+    config: WorkerConfig,
+    workers: Mutex<Vec<Worker>>,
+    // how many workers the pool may grow to when it's busy, see
+    // `ThreadPoolBuilder::max_size`
+    max_workers: usize,
+    // the id the next worker gets. Workers can come and go, so the length of
+    // `workers` isn't good enough for this.
+    next_id: AtomicUsize,
+    // jobs waiting on a delay, and the thread that forwards them to `queue`.
+    // The thread only gets started the first time it's needed.
+    timer: Timer,
+    timer_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    // worker threads that haven't exited yet. It only ever goes down with
+    // `exit_lock` held, so waiting on `exited` can't miss the last one.
+    alive: AtomicUsize,
+    exit_lock: Mutex<()>,
+    exited: Condvar,
+    // jobs that have been sent but not picked up by a worker yet
+    queued: AtomicUsize,
+    // the most `queued` has ever been
+    peak_queued: AtomicUsize,
+    // workers that are in the middle of running a job
+    active: AtomicUsize,
+    // jobs that have run to the end, panicking or not
+    completed: AtomicUsize,
+    // the subset of `completed` that panicked
+    panicked: AtomicUsize,
+    // how long each job took to run
+    durations: Histogram,
+    // the total time jobs spent in the queue before being picked up, and
+    // how many jobs that's over
+    wait_micros: AtomicU64,
+    waits: AtomicU64,
+    // jobs that have been sent but haven't finished running, for `join`
+    outstanding: Mutex<usize>,
+    drained: Condvar,
+    panic_handler: RwLock<Option<Arc<PanicHandler>>>,
+    idle_handler: RwLock<Option<Arc<IdleHandler>>>,
+    starvation_handler: RwLock<Option<Arc<StarvationHandler>>>,
+}
+
+type PanicHandler = dyn Fn(&(dyn Any + Send)) + Send + Sync + 'static;
+type IdleHandler = dyn Fn() + Send + Sync + 'static;
+type StarvationHandler = dyn Fn(Duration) + Send + Sync + 'static;
+
+/// A job, as the pool stores it: just the function we want to pass to the
+/// worker, boxed up. There's nothing super fancy here. These only come back
+/// out of the pool from [`ThreadPool::shutdown_now`].
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How a [`ThreadPool::shutdown_timeout`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownResult {
+    /// Every worker finished and was joined in time.
+    Complete,
+    /// Some workers were still running jobs when the timeout ran out.
+    TimedOut { unfinished: usize },
+}
+
+/// A snapshot of a pool's counters, from [`ThreadPool::stats`].
+///
+/// With the `serde` feature, this can be serialized, to hand out as JSON
+/// say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PoolStats {
+    /// Worker threads in the pool, see [`ThreadPool::len`].
+    pub workers: usize,
+    /// Workers that are running a job, see [`ThreadPool::active_count`].
+    pub active: usize,
+    /// Jobs waiting for a free worker, see [`ThreadPool::queued_count`].
+    pub queued: usize,
+    /// Jobs that have finished, see [`ThreadPool::completed_count`].
+    pub completed: usize,
+    /// How many of the `completed` jobs panicked.
+    pub panicked: usize,
+}
+
+/// How one worker is getting on, from [`ThreadPool::worker_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStat {
+    /// The worker's id, the same one that's in its thread name.
+    pub id: usize,
+    /// Jobs this worker has finished, panicking or not.
+    pub jobs_completed: usize,
+    /// Whether the worker's thread is still running.
+    pub is_alive: bool,
+}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The pool was asked for zero threads.
+    ZeroSize,
+    /// The OS refused to spawn one of the worker threads.
+    Spawn(io::Error),
+    /// The bounds given to [`ThreadPoolBuilder::autoscale`] leave no size the
+    /// pool could be: `min` was zero, or greater than `max`.
+    InvalidAutoscale { min: usize, max: usize },
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => {
+                f.write_str("thread pool size must be greater than zero")
+            }
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn worker thread: {e}"),
+            PoolCreationError::InvalidAutoscale { min, max } => write!(
+                f,
+                "autoscale bounds must be at least 1 with min <= max, got min {min} and max {max}"
+            ),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize | PoolCreationError::InvalidAutoscale { .. } => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+/// The reason a job couldn't be handed to the pool.
+///
+/// Either way the closure is given back, so the caller can run it somewhere
+/// else instead of losing the work.
+pub enum ExecuteError<F> {
+    /// The pool has shut down and is no longer accepting jobs.
+    Shutdown(F),
+    /// Every worker thread has died, so nothing would ever run the job.
+    NoWorkers(F),
+    /// The pool's bounded queue is full, see
+    /// [`ThreadPoolBuilder::queue_capacity`].
+    Full(F),
+    /// The job was meant for a worker the pool doesn't have, see
+    /// [`ThreadPool::execute_on`].
+    UnknownWorker(F),
+}
+
+impl<F> ExecuteError<F> {
+    /// Take back the closure that couldn't be executed.
+    pub fn into_inner(self) -> F {
+        match self {
+            ExecuteError::Shutdown(f)
+            | ExecuteError::NoWorkers(f)
+            | ExecuteError::Full(f)
+            | ExecuteError::UnknownWorker(f) => f,
+        }
+    }
+}
+
+// The closure almost never implements `Debug`, so we only print the variant.
+impl<F> fmt::Debug for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::Shutdown(_) => f.write_str("Shutdown(..)"),
+            ExecuteError::NoWorkers(_) => f.write_str("NoWorkers(..)"),
+            ExecuteError::Full(_) => f.write_str("Full(..)"),
+            ExecuteError::UnknownWorker(_) => f.write_str("UnknownWorker(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::Shutdown(_) => f.write_str("thread pool has shut down"),
+            ExecuteError::NoWorkers(_) => f.write_str("thread pool has no live workers"),
+            ExecuteError::Full(_) => f.write_str("thread pool queue is full"),
+            ExecuteError::UnknownWorker(_) => f.write_str("thread pool has no such worker"),
+        }
+    }
+}
+
+impl<F> Error for ExecuteError<F> {}
+
+impl<F> From<PushError<F>> for ExecuteError<F> {
+    fn from(error: PushError<F>) -> ExecuteError<F> {
+        match error {
+            PushError::Closed(f) => ExecuteError::Shutdown(f),
+            PushError::Full(f) => ExecuteError::Full(f),
+            PushError::NoWorker(f) => ExecuteError::UnknownWorker(f),
+        }
+    }
+}
+
+/// Returned by [`ThreadPool::join`], [`ThreadPool::wait_idle`],
+/// [`ThreadPool::broadcast`] and [`ThreadPool::warmup`] when they're called
+/// from inside one of the pool's own jobs.
+///
+/// The job doing the waiting counts as unfinished itself, and ties up a
+/// worker that would otherwise run what it's waiting for, so the wait could
+/// never end. Rather than hang, the call gives back this error straight
+/// away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReentrantWait;
+
+impl fmt::Display for ReentrantWait {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("waited on a thread pool from inside one of its own jobs")
+    }
+}
+
+impl Error for ReentrantWait {}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero, or if a worker
+    /// thread could not be spawned. Use [`ThreadPool::build`] to handle
+    /// those cases yourself.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::build(size).unwrap()
+    }
+
+    /// Create a new ThreadPool, returning an error instead of panicking.
+    ///
+    /// The size is the number of threads in the pool. A size of zero gives
+    /// back `PoolCreationError::ZeroSize`, and a failure to spawn any of the
+    /// worker threads is handed back as `PoolCreationError::Spawn`.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().size(size).build()
+    }
+
+    /// Create a pool from a [`PoolConfig`], say one that was read from a
+    /// file.
+    ///
+    /// This is the same as building it with the matching
+    /// [`ThreadPoolBuilder`] options, and fails the same way.
+    pub fn from_config(config: PoolConfig) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::from(config).build()
+    }
+
+    /// Create a pool with a single worker that runs jobs one at a time, in
+    /// the order they were submitted.
+    ///
+    /// This is for tests of code that cares about ordering: everything
+    /// still goes through the pool, but nothing runs at the same time as
+    /// anything else, and a job handed over with [`ThreadPool::execute`]
+    /// never starts before one handed over earlier. That holds whatever
+    /// else changes about how jobs get to workers. It only goes away if you
+    /// ask for it, by adding workers or by jumping the queue with
+    /// [`ThreadPool::execute_with_priority`].
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::single_threaded();
+    /// let order = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// for i in 0..10 {
+    ///     let order = Arc::clone(&order);
+    ///     pool.execute(move || order.lock().unwrap().push(i));
+    /// }
+    ///
+    /// pool.join().unwrap();
+    /// assert_eq!(*order.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the worker thread could not be spawned.
+    pub fn single_threaded() -> ThreadPool {
+        ThreadPoolBuilder::new()
+            .size(1)
+            .distribution(Distribution::Shared)
+            .queue_order(QueueOrder::Fifo)
+            .build()
+            .unwrap()
+    }
+
+    /// The pool that's running the current job, if there is one.
+    ///
+    /// This lets code running inside a job hand more work to the same pool
+    /// without having to be given a handle to it. Outside of a worker
+    /// thread, or once the pool's last handle has been dropped, there's no
+    /// pool to give back, so this is `None`.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// assert!(ThreadPool::current().is_none());
+    ///
+    /// let child = pool.spawn(|| {
+    ///     let pool = ThreadPool::current().unwrap();
+    ///     pool.spawn(|| 42)
+    /// });
+    ///
+    /// assert_eq!(child.join().unwrap().join(), Ok(42));
+    /// ```
+    pub fn current() -> Option<ThreadPool> {
+        CURRENT.with_borrow(|current| current.upgrade())
+    }
+
+    /// Make a [`WeakThreadPool`] for this pool, which can be held on to
+    /// without stopping it from shutting down when the last `ThreadPool`
+    /// handle is dropped.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let weak = pool.downgrade();
+    /// assert!(weak.upgrade().is_some());
+    ///
+    /// drop(pool);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    pub fn downgrade(&self) -> WeakThreadPool {
+        WeakThreadPool {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// A handle that can only hand jobs to this pool, for code that
+    /// shouldn't be able to do anything else with it. See [`JobSender`].
+    pub fn sender(&self) -> JobSender {
+        JobSender::new(Arc::clone(&self.inner.shared))
+    }
+
+    /// Hand a job to the pool.
+    ///
+    /// If the pool was built with a bounded queue and it's full, the pool's
+    /// [`RejectionPolicy`] decides what happens. By default this blocks until
+    /// there's room. Be careful doing that from inside a job: if every worker
+    /// ends up waiting for room, nothing is left to make any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job. See [`ThreadPool::try_execute`]
+    /// for a version that gives the closure back instead.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute(f).unwrap();
+    }
+
+    /// Hand every job from `jobs` to the pool, in order.
+    ///
+    /// Returns how many were handed over. If the pool stops taking jobs
+    /// partway through, this stops there too, and the rest of `jobs` is left
+    /// untouched.
+    pub fn execute_all<I, F>(&self, jobs: I) -> usize
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() + Send + 'static,
+    {
+        let mut sent = 0;
+
+        for f in jobs {
+            if self.shared().execute(Priority::Normal, f).is_err() {
+                break;
+            }
+
+            sent += 1;
+        }
+
+        sent
+    }
+
+    /// Hand a job to the pool with a particular [`Priority`].
+    ///
+    /// Workers always pick up the most urgent job waiting, so a `High` job
+    /// jumps ahead of any `Normal` or `Low` ones that are still queued. Jobs
+    /// that are already running aren't interrupted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared().execute(priority, f).unwrap();
+    }
+
+    /// Hand a job to the pool, or get it back if nobody can run it.
+    ///
+    /// This fails with `ExecuteError::Shutdown` once the pool has started
+    /// shutting down, and with `ExecuteError::NoWorkers` if every worker
+    /// thread has died. A full bounded queue is dealt with by the pool's
+    /// [`RejectionPolicy`], the same as in [`ThreadPool::execute`].
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared().execute(Priority::Normal, f)
+    }
+
+    /// Hand a job to one worker in particular.
+    ///
+    /// Only the worker with id `worker_id` will run it, after anything else
+    /// that's been sent to it this way but ahead of the pool's shared queue.
+    /// That's useful when each worker owns some state, in a thread-local
+    /// say, that the job needs to get at. Ids are the ones in the thread
+    /// names, see [`ThreadPoolBuilder::thread_name_prefix`].
+    ///
+    /// Fails with `ExecuteError::UnknownWorker` if there's no live worker
+    /// with that id, including one that's been removed, is on its way out,
+    /// or has died, and
+    /// with `ExecuteError::Shutdown` once the pool has shut down. These jobs
+    /// skip a bounded queue's capacity limit, but not a pause: while the
+    /// pool is paused they wait like everything else, see
+    /// [`ThreadPool::pause`]. If the worker is removed before it gets to the
+    /// job, it still runs it on the way out, unless the pool is paused then,
+    /// in which case the job goes back in the shared queue, and whichever
+    /// worker is free runs it once the pool resumes.
+    pub fn execute_on<F>(&self, worker_id: usize, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared().submit_to(worker_id, f)
+    }
+
+    /// Hand a job to the pool without ever blocking.
+    ///
+    /// This is [`ThreadPool::try_execute`], except that if the pool has a
+    /// bounded queue and it's full, the closure comes straight back as
+    /// `ExecuteError::Full`, whatever the pool's [`RejectionPolicy`] says.
+    pub fn try_execute_now<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared().submit(Priority::Normal, f, PushMode::Fail)
+    }
+
+    /// Hand a job to the pool, but don't let it run until `delay` has passed.
+    ///
+    /// The job joins the back of the normal queue once it comes due, so it
+    /// may start a little later than that if every worker is busy. Until
+    /// then it doesn't count as queued, so [`ThreadPool::join`] won't wait
+    /// for it. Jobs that haven't come due when the pool shuts down are
+    /// dropped without running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool has already shut down, or if the timer thread
+    /// couldn't be spawned.
+    pub fn execute_after<F>(&self, delay: Duration, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.inner.shared;
+        assert!(!shared.queue.is_closed(), "thread pool has shut down");
+
+        Shared::start_timer(shared).unwrap();
+        // If we lose a race with shutdown the job gets dropped here, which is
+        // what would have happened to it as a delayed job anyway.
+        let _ = shared
+            .timer
+            .schedule(Instant::now() + delay, Action::Enqueue(Box::new(f)));
+    }
+
+    /// Run a job over and over, waiting `interval` between the end of one
+    /// run and the start of the next.
+    ///
+    /// Each run goes through the normal queue like any other job. The first
+    /// run happens after one `interval`. Use the returned handle to stop it;
+    /// it also stops when the pool shuts down. A run that panics is reported
+    /// like any other panicking job and doesn't stop the next one.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::execute_after`].
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TaskHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let shared = &self.inner.shared;
+        assert!(!shared.queue.is_closed(), "thread pool has shut down");
+        Shared::start_timer(shared).unwrap();
+
+        let handle = TaskHandle::new();
+        schedule_every(Arc::clone(shared), interval, Arc::new(f), handle.clone());
+        handle
+    }
+
+    /// Hand a job to the pool, and run it again if it panics.
+    ///
+    /// The job gets up to `max_attempts` tries in all; a `max_attempts` of
+    /// zero still runs it once. Each retry goes to the back of the queue.
+    /// Panics from attempts that get retried aren't passed to the panic
+    /// handler, only the one from the last attempt is, once the job has
+    /// run out of tries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute_with_retry<F>(&self, max_attempts: usize, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let job = retry_job(
+            Arc::clone(&self.inner.shared),
+            Arc::new(f),
+            1,
+            max_attempts.max(1),
+        );
+        self.execute(job);
+    }
+
+    /// Hand the pool a job that gets called over and over until it returns
+    /// `ControlFlow::Break`.
+    ///
+    /// After each call that returns `ControlFlow::Continue`, the job goes
+    /// to the back of the queue, so a long-running job done in small steps
+    /// shares its worker with everything else rather than tying it up. If
+    /// the pool shuts down while the job's still going, it carries on to
+    /// the end on the worker it's on, like any other job that's started. A
+    /// panic ends it there, and is reported like any other job's.
+    ///
+    /// ```
+    /// use std::{ops::ControlFlow, sync::{Arc, Mutex}};
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// // Hold off until everything's queued, so the order is certain.
+    /// pool.pause();
+    ///
+    /// let mut calls = 0;
+    /// let polling = Arc::clone(&log);
+    /// pool.execute_polling(move || {
+    ///     calls += 1;
+    ///     polling.lock().unwrap().push("poll");
+    ///     if calls == 5 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    /// });
+    ///
+    /// let other = Arc::clone(&log);
+    /// pool.execute(move || other.lock().unwrap().push("other"));
+    ///
+    /// pool.resume();
+    /// pool.join().unwrap();
+    ///
+    /// assert_eq!(*log.lock().unwrap(), ["poll", "other", "poll", "poll", "poll", "poll"]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute_polling<F>(&self, f: F)
+    where
+        F: FnMut() -> ControlFlow<()> + Send + 'static,
+    {
+        let job = polling_job(Arc::clone(&self.inner.shared), f);
+        self.execute(job);
+    }
+
+    /// Hand a job to the pool along with a [`CancelToken`] that gets
+    /// cancelled once the job has been running for `timeout`.
+    ///
+    /// This is cooperative cancellation, not preemption: nothing stops the
+    /// job from running past the timeout. It's up to the job to check
+    /// `token.is_cancelled()` now and then and return early. The clock
+    /// starts when a worker picks the job up, not when it's submitted.
+    ///
+    /// If the pool starts shutting down before the timeout, the token is
+    /// cancelled there and then, so shutting down doesn't wait out the
+    /// rest of the timeout, or forever on a job that's parked on the token.
+    /// A job that only starts once shutdown is under way gets a token
+    /// that's already cancelled.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::execute_after`].
+    pub fn execute_with_timeout<F>(&self, timeout: Duration, f: F)
+    where
+        F: FnOnce(CancelToken) + Send + 'static,
+    {
+        let shared = &self.inner.shared;
+        assert!(!shared.queue.is_closed(), "thread pool has shut down");
+        Shared::start_timer(shared).unwrap();
+
+        let pool = Arc::clone(shared);
+        self.execute(move || {
+            let token = CancelToken::new();
+            let watchdog = CancelOnDrop(token.clone());
+
+            // The watchdog runs on the timer thread itself, so it fires on
+            // time even when every worker is tied up. It cancels the token
+            // however it goes, so if the pool starts shutting down first,
+            // and the timer throws the watchdog away, the job is told to
+            // stop rather than left for shutdown to wait on forever.
+            let _ = pool.timer.schedule(
+                Instant::now() + timeout,
+                Action::Inline(Box::new(move || drop(watchdog))),
+            );
+
+            f(token);
+        });
+    }
+
+    /// Hand a job to the pool along with a clone of `token`, so that it
+    /// can keep an eye on it or park until it's cancelled.
+    ///
+    /// Like [`ThreadPool::execute_with_timeout`], it's up to the job to
+    /// notice; cancelling the token doesn't stop the job by itself. A job
+    /// that's still queued when the token is cancelled is run all the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute_with_token<F>(&self, token: &CancelToken, f: F)
+    where
+        F: FnOnce(CancelToken) + Send + 'static,
+    {
+        let token = token.clone();
+        self.execute(move || f(token));
+    }
+
+    /// Hand a job to the pool, and get back an [`AbortHandle`] that can
+    /// stop it from running while it's still queued.
+    ///
+    /// An aborted job is still taken off the queue by a worker, which just
+    /// throws it away instead of running it. Aborting a job that's already
+    /// started does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute_cancelable<F>(&self, f: F) -> AbortHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (job, handle) = AbortHandle::wrap(f);
+        self.execute(job);
+        handle
+    }
+
+    /// Call `handler` with the panic payload whenever a job panics.
+    ///
+    /// The handler runs on the worker thread that caught the panic, right
+    /// after the job unwinds. Setting a new handler replaces the old one.
+    /// Without a handler, panics are just logged (see the `logging` feature).
+    pub fn set_panic_handler(&self, handler: impl Fn(&(dyn Any + Send)) + Send + Sync + 'static) {
+        *self.shared().panic_handler.write().unwrap() = Some(Arc::new(handler));
+    }
+
+    /// Call `callback` every time the pool goes from busy to idle.
+    ///
+    /// It fires once each time the last queued or running job finishes,
+    /// not again until more jobs have come and gone. It runs on whichever
+    /// thread finished that last job, usually a worker. Setting a new
+    /// callback replaces the old one.
+    pub fn on_idle(&self, callback: impl Fn() + Send + Sync + 'static) {
+        *self.shared().idle_handler.write().unwrap() = Some(Arc::new(callback));
+    }
+
+    /// Call `callback` when a job has been waiting for a worker for longer
+    /// than the pool's [`ThreadPoolBuilder::starvation_threshold`], with
+    /// how long it's been waiting.
+    ///
+    /// It runs on the pool's timer thread, so it should be quick. Setting a
+    /// new callback replaces the old one. Without a callback, starvation is
+    /// just logged (see the `logging` feature).
+    pub fn on_starvation(&self, callback: impl Fn(Duration) + Send + Sync + 'static) {
+        *self.shared().starvation_handler.write().unwrap() = Some(Arc::new(callback));
+    }
+
+    /// Run a job on the pool and get a handle to its return value.
+    ///
+    /// If the job panics, [`JobHandle::join`] gives back `JobError::Panic`,
+    /// with the panic's message, instead of the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = handle::job_with_handle(&self.inner.shared, f);
+        self.execute(job);
+        handle
+    }
+
+    /// Run every closure in `fs` on the pool, and get back a [`JobSet`] to
+    /// collect their results from, either all together or as they finish.
+    ///
+    /// Unlike [`ThreadPool::join_all`], this doesn't block, so the caller
+    /// can get on with something else, or start handling the first results
+    /// while the rest are still running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the jobs, the same as
+    /// [`ThreadPool::execute`].
+    pub fn spawn_all<I, F, T>(&self, fs: I) -> JobSet<T>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JobSet::new(fs.into_iter().map(|f| self.spawn(f)).collect())
+    }
+
+    /// Run `f`, a blocking operation, from inside a job, with another worker
+    /// brought in to cover for this one until it returns.
+    ///
+    /// A job that sits blocked ties up its worker, and enough of them can
+    /// leave nobody to run the rest of the queue. Wrapping the blocking
+    /// part in this spawns a stand-in worker first, so the queue keeps
+    /// moving, and retires it again once `f` returns. The stand-in doesn't
+    /// count towards [`ThreadPoolBuilder::max_size`], and finishes whatever
+    /// job it's running before it goes.
+    ///
+    /// Called anywhere but on one of this pool's workers, there's no worker
+    /// to cover for, so this just calls `f`. The same goes if the pool is
+    /// shutting down or the stand-in can't be spawned.
+    ///
+    /// ```
+    /// use std::{sync::mpsc, thread, time::Duration};
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// pool.execute(|| {
+    ///     let pool = ThreadPool::current().unwrap();
+    ///     pool.block_in_place(|| thread::sleep(Duration::from_millis(500)));
+    /// });
+    /// pool.execute(move || tx.send(()).unwrap());
+    ///
+    /// // The pool's only worker is still asleep, but the job ran anyway.
+    /// rx.recv_timeout(Duration::from_millis(250)).unwrap();
+    /// ```
+    pub fn block_in_place<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        if !self.on_own_worker() {
+            return f();
+        }
+
+        // Retire the stand-in on the way out, even if `f` panics.
+        let _stand_in = StandIn {
+            shared: self.shared(),
+            retire: Shared::spawn_stand_in(&self.inner.shared),
+        };
+
+        f()
+    }
+
+    /// Run `f` once on every worker thread, and wait for all of them to
+    /// finish.
+    ///
+    /// Each worker calls it with its own id, after any job it's already
+    /// running. That makes this a way to set up thread-locals on a pool
+    /// that's already been built; for a pool that's still being built see
+    /// [`ThreadPoolBuilder::worker_init`]. A run that panics is reported
+    /// like any other job and doesn't stop the rest.
+    ///
+    /// Called from one of this pool's own jobs, it would be waiting on the
+    /// worker running it, so it gives back [`ReentrantWait`] instead of
+    /// hanging, without running `f` anywhere.
+    pub fn broadcast<F>(&self, f: F) -> Result<(), ReentrantWait>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        if self.on_own_worker() {
+            warn!(
+                "Pool {} was broadcast to from inside one of its own jobs, which would never return",
+                self.id()
+            );
+            return Err(ReentrantWait);
+        }
+
+        let f = Arc::new(f);
+        let shared = self.shared();
+
+        let handles: Vec<_> = shared
+            .worker_ids()
+            .into_iter()
+            .filter_map(|id| {
+                let f = Arc::clone(&f);
+                let (job, handle) = handle::job_with_handle(&self.inner.shared, move || f(id));
+
+                // A worker that's exited or been told to stop since we
+                // asked isn't one we need to wait for.
+                shared.submit_to(id, pinned(id, job)).ok().map(|()| handle)
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        Ok(())
+    }
+
+    /// Have every worker stop as soon as it's done with its current job,
+    /// and wait there until all of them have, then let them carry on
+    /// together.
+    ///
+    /// This is a synchronization point for work that runs in phases. The
+    /// barrier goes ahead of anything still in the queue, and unlike
+    /// [`ThreadPool::broadcast`] it doesn't block the caller. Workers that
+    /// exit before they reach it, because they were removed say, aren't
+    /// waited for. Returns how many workers are taking part.
+    pub fn barrier(&self) -> usize {
+        let shared = self.shared();
+        let rendezvous = Rendezvous::new();
+
+        let taking_part = shared
+            .worker_ids()
+            .into_iter()
+            .filter(|&id| {
+                let job = pinned(id, Rendezvous::job(&rendezvous));
+                shared.submit_to(id, job).is_ok()
+            })
+            .count();
+
+        rendezvous.seal();
+        taking_part
+    }
+
+    /// Block until every worker thread is up and taking jobs.
+    ///
+    /// Workers are spawned when the pool is built, but the OS may take a
+    /// moment to actually get them going. Call this once at startup so the
+    /// first real job doesn't have to wait for that. It works by sending a
+    /// job that does nothing to each worker, so it also waits for any work
+    /// they already have, and doesn't return while the pool is paused.
+    ///
+    /// Like [`ThreadPool::broadcast`], this gives back [`ReentrantWait`]
+    /// if it's called from one of the pool's own jobs.
+    pub fn warmup(&self) -> Result<(), ReentrantWait> {
+        self.broadcast(|_| {})
+    }
+
+    /// Apply `f` to every item on the pool and collect the results in the
+    /// same order as `items`.
+    ///
+    /// Each item becomes its own job, and this blocks until all of them have
+    /// finished.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let squares = pool.map((0..10).collect(), |x| x * x);
+    ///
+    /// assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the jobs, or if `f` panics on any of
+    /// the items.
+    pub fn map<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+
+        let jobs = items.into_iter().map(|item| {
+            let f = Arc::clone(&f);
+            move || f(item)
+        });
+
+        self.run_in_order("map", jobs)
+    }
+
+    /// Apply `f` to every item on the pool, and get the results back as
+    /// each job finishes, in whatever order that is.
+    ///
+    /// Each item becomes its own job. The receiver yields every result
+    /// exactly once, then disconnects after the last one, so it can just be
+    /// iterated over. An item whose job panics, or is thrown away because
+    /// the pool shut down, has no result.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let results = pool.map_unordered((0..10).collect(), |x| x * x);
+    /// let mut squares: Vec<_> = results.iter().collect();
+    ///
+    /// squares.sort();
+    /// assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the jobs, the same as
+    /// [`ThreadPool::execute`].
+    pub fn map_unordered<T, R, F>(&self, items: Vec<T>, f: F) -> mpsc::Receiver<R>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let (tx, rx) = mpsc::channel();
+
+        // Every job gets its own sender, and the channel disconnects once
+        // the last of them has been dropped along with its job.
+        for item in items {
+            let f = Arc::clone(&f);
+            let tx = tx.clone();
+
+            self.execute(move || {
+                // The caller may have stopped listening, which is fine.
+                let _ = tx.send(f(item));
+            });
+        }
+
+        rx
+    }
+
+    /// Apply `map` to every item on the pool, then combine the results with
+    /// `reduce`, starting from `identity`.
+    ///
+    /// The mapping runs in parallel, one job per item, and the reducing
+    /// happens on the calling thread once they've all finished, in the same
+    /// order as `items`. With no items, this is just `identity`.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let sum = pool.map_reduce((0..100).collect(), |x| x, |a, b| a + b, 0);
+    ///
+    /// assert_eq!(sum, 4950);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::map`].
+    pub fn map_reduce<T, R, F, G>(&self, items: Vec<T>, map: F, reduce: G, identity: R) -> R
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+        G: Fn(R, R) -> R,
+    {
+        self.map(items, map).into_iter().fold(identity, reduce)
+    }
+
+    /// Run every closure in `fs` on the pool and wait for all of them.
+    ///
+    /// The results come back in the same order as `fs`. A closure that
+    /// panics doesn't affect the others; its slot is just
+    /// `Err(JobError::Panic(_))`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the jobs, the same as
+    /// [`ThreadPool::execute`].
+    pub fn join_all<T, F>(&self, fs: Vec<F>) -> Vec<Result<T, JobError>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let handles: Vec<_> = fs.into_iter().map(|f| self.spawn(f)).collect();
+        handles.into_iter().map(JobHandle::join).collect()
+    }
+
+    /// Run every closure in `fs` on the pool, and collect what they return
+    /// into any collection that can be built from an iterator, in the same
+    /// order as `fs`.
+    ///
+    /// This blocks until they've all finished. Unlike
+    /// [`ThreadPool::join_all`], there's nowhere to put a failure, so a
+    /// closure that panics takes this call down with it, as it would in
+    /// [`ThreadPool::map`].
+    ///
+    /// ```
+    /// use std::collections::HashSet;
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    ///
+    /// let squares: Vec<u32> = pool.par_collect((0..5).map(|i| move || i * i));
+    /// assert_eq!(squares, [0, 1, 4, 9, 16]);
+    ///
+    /// let parities: HashSet<u32> = pool.par_collect((0..5).map(|i| move || i % 2));
+    /// assert_eq!(parities, HashSet::from([0, 1]));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the jobs, or if any of them panics.
+    pub fn par_collect<F, T, C>(&self, fs: impl IntoIterator<Item = F>) -> C
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+        C: FromIterator<T>,
+    {
+        self.run_in_order("par_collect", fs)
+    }
+
+    // Spawn every one of `jobs`, then wait for them and collect what they
+    // return in the order they came in. A job that fails, panicking say,
+    // takes the caller down with it, with `what` in the message to say
+    // where it came from.
+    fn run_in_order<F, T, C>(&self, what: &str, jobs: impl IntoIterator<Item = F>) -> C
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+        C: FromIterator<T>,
+    {
+        let handles: Vec<_> = jobs.into_iter().map(|f| self.spawn(f)).collect();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| panic!("{what} job failed: {e:?}"))
+            })
+            .collect()
+    }
+
+    /// Run a future to completion on the pool.
+    ///
+    /// Each poll runs as an ordinary job, and waking the future queues up
+    /// the next one, so it shares the workers with everything else. If the
+    /// future panics, the panic is reported like any other job's and the
+    /// future is dropped. One that's still waiting when the pool shuts down
+    /// is dropped without finishing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the first poll, the same as
+    /// [`ThreadPool::execute`].
+    #[cfg(feature = "futures")]
+    pub fn spawn_future<F>(&self, future: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        task::spawn(self, future);
+    }
+
+    /// Run a future to completion on the current thread, blocking until
+    /// it's done.
+    ///
+    /// The future itself is polled here rather than on a worker, but
+    /// anything it hands to the pool, with [`ThreadPool::spawn_future`] or
+    /// otherwise, runs on the workers as normal. A future that's ready
+    /// straight away returns without blocking at all.
+    ///
+    /// Calling this from inside a job ties up that worker until the future
+    /// finishes, which can deadlock if the future is waiting on the pool.
+    #[cfg(feature = "futures")]
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        task::block_on(future)
+    }
+
+    /// Run jobs on the pool that can borrow from the caller's stack.
+    ///
+    /// This works like [`std::thread::scope`]: `f` gets a [`Scope`] to hand
+    /// jobs to, and `scope` doesn't return until every one of them has
+    /// finished, so they can borrow anything that outlives the call.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let numbers = [1, 2, 3, 4, 5, 6, 7, 8];
+    /// let total = AtomicU32::new(0);
+    ///
+    /// pool.scope(|s| {
+    ///     for chunk in numbers.chunks(2) {
+    ///         let total = &total;
+    ///         s.execute(move || {
+    ///             total.fetch_add(chunk.iter().sum(), Ordering::Relaxed);
+    ///         });
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(total.into_inner(), 36);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `f` or any of the jobs panic, this panics too, but only after
+    /// every job has finished. Calling it from inside a job can deadlock if
+    /// there aren't enough workers left to run the scoped jobs.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        scope::scope(self, f)
+    }
+
+    /// A number that tells this pool apart from any other in the process.
+    ///
+    /// Every pool gets a new one when it's built, and every handle to the
+    /// same pool gives the same one. Log messages from the `logging`
+    /// feature include it, so you can tell which pool they came from.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let (a, b) = (ThreadPool::new(1), ThreadPool::new(1));
+    /// assert_ne!(a.id(), b.id());
+    /// assert_eq!(a.id(), a.clone().id());
+    /// ```
+    pub fn id(&self) -> u64 {
+        self.shared().id
+    }
+
+    /// The number of worker threads in the pool.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// assert_eq!(ThreadPool::new(4).len(), 4);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.shared().workers.lock().unwrap().len()
+    }
+
+    /// Whether the pool has no worker threads at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Spawn `n` more worker threads.
+    ///
+    /// The new workers get fresh ids and start taking jobs off the same
+    /// queue straight away. If one of them fails to spawn, the ones before
+    /// it are kept and the error is handed back. Once the pool has shut
+    /// down this does nothing.
+    pub fn add_workers(&self, n: usize) -> io::Result<()> {
+        Shared::spawn_workers(&self.inner.shared, n)
+    }
+
+    /// Stop `n` of the worker threads and wait for them to exit.
+    ///
+    /// A worker that's in the middle of a job finishes it first. Jobs still
+    /// in the queue are left for the remaining workers, so removing every
+    /// worker leaves them stuck until more are added. Asking for more
+    /// workers than the pool has removes all of them. Returns how many were
+    /// removed.
+    pub fn remove_workers(&self, n: usize) -> usize {
+        self.shared().remove_workers(n)
+    }
+
+    /// Throw away every job that's still waiting in the queue, and return
+    /// how many there were.
+    ///
+    /// Jobs that are already running aren't touched, and neither are
+    /// delayed jobs that haven't come due yet. Handles to discarded jobs
+    /// report `JobError::Canceled`.
+    pub fn clear_queue(&self) -> usize {
+        let shared = self.shared();
+        shared.discard(shared.queue.clear())
+    }
+
+    /// Stop workers from starting any more jobs until
+    /// [`ThreadPool::resume`] is called.
+    ///
+    /// Jobs that are already running carry on to the end, and new ones can
+    /// still be submitted; they just wait in the queue, including those
+    /// sent to one worker with [`ThreadPool::execute_on`]. [`ThreadPool::join`]
+    /// won't return while anything is left waiting. Shutting the pool down
+    /// lifts the pause, so the queue still gets drained.
+    pub fn pause(&self) {
+        self.shared().queue.set_paused(true);
+    }
+
+    /// Let workers start taking jobs again after [`ThreadPool::pause`].
+    pub fn resume(&self) {
+        self.shared().queue.set_paused(false);
+    }
+
+    /// Block until every queued and running job has finished.
+    ///
+    /// Unlike [`ThreadPool::shutdown`] the pool is still usable afterwards,
+    /// so you can submit a batch, `join`, and submit the next one.
+    ///
+    /// Jobs submitted from other threads while we're waiting are waited on
+    /// too: this returns the first time the pool is seen with nothing left
+    /// to do, which may never happen if jobs keep arriving.
+    ///
+    /// Called from one of this pool's own jobs, it would be waiting on
+    /// itself, so it gives back [`ReentrantWait`] instead of hanging.
+    pub fn join(&self) -> Result<(), ReentrantWait> {
+        if self.on_own_worker() {
+            warn!(
+                "Pool {} was joined from inside one of its own jobs, which would never return",
+                self.id()
+            );
+            return Err(ReentrantWait);
+        }
+
+        let shared = self.shared();
+        let mut outstanding = shared.outstanding.lock().unwrap();
+
+        while *outstanding > 0 {
+            outstanding = shared.drained.wait(outstanding).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Block until the pool is idle: no job running and none waiting in the
+    /// queue.
+    ///
+    /// This is [`ThreadPool::join`] under a name that says what it checks
+    /// for. It returns at the first moment the pool is seen idle, so a job
+    /// submitted from another thread right after may already be running by
+    /// the time it returns. Delayed jobs that haven't come due don't count.
+    pub fn wait_idle(&self) -> Result<(), ReentrantWait> {
+        self.join()
+    }
+
+    /// Shut the pool down, waiting for every queued job to finish.
+    ///
+    /// This is the same thing dropping the last handle does, but it lets you
+    /// pick the exact point where it happens. Any other handles to the pool
+    /// stop accepting jobs too.
+    pub fn shutdown(self) {
+        self.shared().shutdown();
+    }
+
+    /// Shut the pool down without running the jobs that are still queued,
+    /// and hand them back.
+    ///
+    /// Jobs that are already running are left to finish, and this waits for
+    /// them. Everything a worker hasn't started yet is taken out of the
+    /// queue in one go and returned in no particular order, to be saved or
+    /// run somewhere else. Delayed jobs that haven't come due are dropped.
+    /// Any other handles to the pool stop accepting jobs too.
+    pub fn shutdown_now(self) -> Vec<Job> {
+        let shared = self.shared();
+        let jobs = shared.queue.close_and_clear();
+
+        shared.uncount(jobs.len());
+        shared.shutdown();
+        jobs
+    }
+
+    /// Shut the pool down, but give up waiting once `timeout` has passed.
+    ///
+    /// Threads can't be killed safely, so any worker still busy when the
+    /// time runs out is left to finish its job in the background and is
+    /// reported in the result instead of being joined.
+    pub fn shutdown_timeout(self, timeout: Duration) -> ShutdownResult {
+        self.shared().shutdown_timeout(timeout)
+    }
+
+    /// Shut the pool down gracefully as soon as `token` is cancelled.
+    ///
+    /// This is for services that stop when a flag gets set, say by a ctrl-c
+    /// handler: the pool notices within 100ms and shuts down the same way
+    /// [`ThreadPool::shutdown`] does, finishing the jobs it already has.
+    /// From then on, every handle's submissions fail with
+    /// [`ExecuteError::Shutdown`]. Hands the pool back, for chaining onto
+    /// [`ThreadPool::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`ThreadPool::execute_after`].
+    pub fn with_shutdown_signal(self, token: CancelToken) -> ThreadPool {
+        let shared = &self.inner.shared;
+        assert!(!shared.queue.is_closed(), "thread pool has shut down");
+
+        Shared::start_timer(shared).unwrap();
+        watch_shutdown_signal(Arc::clone(shared), token);
+        self
+    }
+
+    /// Whether the pool has been shut down, by [`ThreadPool::shutdown`] or
+    /// one of its variants on any handle.
+    ///
+    /// Once this is true it stays true, and every submission fails with
+    /// [`ExecuteError::Shutdown`].
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let other = pool.clone();
+    /// assert!(!other.is_shutdown());
+    ///
+    /// pool.shutdown();
+    /// assert!(other.is_shutdown());
+    /// ```
+    pub fn is_shutdown(&self) -> bool {
+        self.shared().queue.is_closed()
+    }
+
+    /// Whether a job submitted now would be taken: the pool hasn't been shut
+    /// down, and it still has at least one worker alive to run it.
+    ///
+    /// Another thread can always change that straight after, so a `true`
+    /// here doesn't promise the next `execute` will work.
+    pub fn is_accepting(&self) -> bool {
+        let shared = self.shared();
+        !shared.queue.is_closed() && shared.alive.load(Ordering::Acquire) > 0
+    }
+
+    /// The number of jobs waiting in the queue for a free worker.
+    ///
+    /// Jobs that are already running aren't counted here, see
+    /// [`ThreadPool::active_count`] for those.
+    pub fn queued_count(&self) -> usize {
+        self.shared().queued.load(Ordering::Relaxed)
+    }
+
+    /// The most jobs that have ever been waiting in the queue at once.
+    ///
+    /// Useful for picking a [`ThreadPoolBuilder::queue_capacity`]: it's the
+    /// backlog the pool has actually had to deal with so far.
+    pub fn peak_queue_depth(&self) -> usize {
+        self.shared().peak_queued.load(Ordering::Relaxed)
+    }
+
+    /// The number of jobs the pool has finished since it was created.
+    ///
+    /// Jobs that panicked still count, since they ran.
+    pub fn completed_count(&self) -> usize {
+        self.shared().completed.load(Ordering::Relaxed)
+    }
+
+    /// How many of the pool's finished jobs panicked, out of the
+    /// [`ThreadPool::completed_count`].
+    ///
+    /// Every panic counts, whether or not there's a panic handler. A job
+    /// from [`ThreadPool::execute_with_retry`] only counts if its last try
+    /// panics, since the others are caught before the worker sees them.
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// pool.set_panic_handler(|_| {});
+    ///
+    /// for i in 0..10 {
+    ///     pool.execute(move || assert!(i % 3 != 0));
+    /// }
+    ///
+    /// pool.join().unwrap();
+    /// assert_eq!(pool.completed_count(), 10);
+    /// assert_eq!(pool.panicked_count(), 4);
+    /// ```
+    pub fn panicked_count(&self) -> usize {
+        self.shared().panicked.load(Ordering::Acquire)
+    }
+
+    /// The number of workers that are running a job right now.
+    ///
+    /// This is a snapshot, so it may already be out of date by the time you
+    /// look at it.
+    pub fn active_count(&self) -> usize {
+        self.shared().active.load(Ordering::Relaxed)
+    }
+
+    /// The 50th, 95th and 99th percentiles of how long jobs have taken to
+    /// run, in that order.
+    ///
+    /// Only the time spent running counts, not the time spent waiting in
+    /// the queue. The durations are kept in buckets rather than one by one,
+    /// so each figure is rounded up by as much as a quarter. All zero until
+    /// a job has finished.
+    pub fn duration_percentiles(&self) -> (Duration, Duration, Duration) {
+        let durations = &self.shared().durations;
+
+        (
+            durations.quantile(0.50),
+            durations.quantile(0.95),
+            durations.quantile(0.99),
+        )
+    }
+
+    /// The average time jobs have spent waiting in the queue before a
+    /// worker picked them up.
+    ///
+    /// If this keeps climbing, the pool could do with more workers. Delayed
+    /// jobs only count from when they came due. Zero until a job has been
+    /// picked up.
+    pub fn avg_queue_wait(&self) -> Duration {
+        let shared = self.shared();
+        let waits = shared.waits.load(Ordering::Relaxed);
+
+        if waits == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_micros(shared.wait_micros.load(Ordering::Relaxed) / waits)
+    }
+
+    /// How each worker is getting on, in the order they were spawned.
+    ///
+    /// Handy for spotting a worker that's stuck, or one that's doing far
+    /// more than its share. Workers that have been removed aren't listed, so
+    /// once any have, the counts here no longer add up to
+    /// [`ThreadPool::completed_count`].
+    pub fn worker_stats(&self) -> Vec<WorkerStat> {
+        self.shared()
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| WorkerStat {
+                id: worker.id,
+                jobs_completed: worker.completed.load(Ordering::Relaxed),
+                is_alive: worker
+                    .thread
+                    .as_ref()
+                    .is_some_and(|thread| !thread.is_finished()),
+            })
+            .collect()
+    }
+
+    /// Take all of the pool's counters at once.
+    ///
+    /// Each counter is read separately, so like the individual `*_count`
+    /// methods this can be slightly out of date, but `panicked` is never
+    /// more than `completed`.
+    pub fn stats(&self) -> PoolStats {
+        let shared = self.shared();
+        let panicked = shared.panicked.load(Ordering::Acquire);
+
+        PoolStats {
+            workers: self.len(),
+            active: shared.active.load(Ordering::Relaxed),
+            queued: shared.queued.load(Ordering::Relaxed),
+            completed: shared.completed.load(Ordering::Relaxed),
+            panicked,
+        }
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.inner.shared
+    }
+
+    // Whether we're on one of this pool's worker threads.
+    fn on_own_worker(&self) -> bool {
+        CURRENT.with_borrow(|current| current.inner.as_ptr() == Arc::as_ptr(&self.inner))
+    }
+}
+
+impl Shared {
+    fn spawn_workers(this: &Arc<Shared>, n: usize) -> io::Result<()> {
+        // Hold the lock the whole way through, so a shutdown that's racing
+        // with us either sees our workers when it goes to join them, or
+        // closed the queue before we looked.
+        let mut workers = this.workers.lock().unwrap();
+
+        if this.queue.is_closed() {
+            return Ok(());
+        }
+
+        for _ in 0..n {
+            let id = this.next_id.fetch_add(1, Ordering::Relaxed);
+            workers.push(Worker::new(id, Arc::clone(this), false)?);
+        }
+
+        Ok(())
+    }
+
+    // Spawn a worker to cover for one that's about to block, and hand back
+    // its flag for retiring it again. Gives back `None` if there's no
+    // stand-in to retire, because the pool is shutting down or the thread
+    // wouldn't spawn.
+    fn spawn_stand_in(this: &Arc<Shared>) -> Option<Arc<AtomicBool>> {
+        let mut workers = this.workers.lock().unwrap();
+
+        if this.queue.is_closed() {
+            return None;
+        }
+
+        let id = this.next_id.fetch_add(1, Ordering::Relaxed);
+
+        match Worker::new(id, Arc::clone(this), false) {
+            Ok(worker) => {
+                debug!(
+                    "Pool {} spawned worker {id} to stand in for a blocked one",
+                    this.id
+                );
+                let retire = Arc::clone(&worker.retire);
+                workers.push(worker);
+                Some(retire)
+            }
+            Err(e) => {
+                warn!("Pool {} couldn't spawn a stand-in worker: {e}", this.id);
+                None
+            }
+        }
+    }
+
+    // `submit`, but with a full queue handled by the rejection policy rather
+    // than handed back.
+    fn execute<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.rejection == RejectionPolicy::Block {
+            return self.submit(priority, f, PushMode::Wait);
+        }
+
+        match self.submit(priority, f, PushMode::Fail) {
+            Err(ExecuteError::Full(f)) if self.rejection == RejectionPolicy::CallerRuns => {
+                f();
+                Ok(())
+            }
+            Err(ExecuteError::Full(_)) => {
+                debug!("Pool {} queue is full; discarding a job", self.id);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn submit<F>(&self, priority: Priority, f: F, mode: PushMode) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // A pool that's been shut down has no workers either, but saying so
+        // would be misleading, so check that first.
+        if self.queue.is_closed() {
+            return Err(ExecuteError::Shutdown(f));
+        }
+
+        if self.alive.load(Ordering::Acquire) == 0 {
+            return Err(ExecuteError::NoWorkers(f));
+        }
+
+        if self.distribution == Distribution::RoundRobin {
+            return self.submit_round_robin(f);
+        }
+
+        self.queue
+            .push(priority, f, mode, || self.count_job())
+            .map_err(ExecuteError::from)?;
+
+        self.grow();
+        Ok(())
+    }
+
+    // Hand a job to whichever worker's turn it is.
+    fn submit_round_robin<F>(&self, mut f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let ids = self.worker_ids();
+
+        // A worker that's exited since we looked won't take the job, so try
+        // the next one along.
+        for _ in 0..ids.len() {
+            let turn = self.next_turn.fetch_add(1, Ordering::Relaxed);
+
+            match self.submit_to(ids[turn % ids.len()], f) {
+                Err(ExecuteError::UnknownWorker(job)) => f = job,
+                result => return result,
+            }
+        }
+
+        Err(ExecuteError::NoWorkers(f))
+    }
+
+    // The ids of the workers that are still taking jobs, leaving out any
+    // that have been told to stop.
+    fn worker_ids(&self) -> Vec<usize> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .iter()
+            .filter(|worker| !worker.retiring())
+            .map(|worker| worker.id)
+            .collect()
+    }
+
+    // Spawn an extra worker if every worker is busy and there are jobs
+    // waiting, as long as the pool hasn't reached its maximum size.
+    fn grow(&self) {
+        if self.queue.backlog() == 0 {
+            return;
+        }
+
+        let Some(this) = self.me.upgrade() else {
+            return;
+        };
+
+        let mut workers = self.workers.lock().unwrap();
+
+        if workers.len() >= self.max_workers || self.queue.is_closed() {
+            return;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        match Worker::new(id, this, true) {
+            Ok(worker) => {
+                debug!("Pool {} is busy; spawned extra worker {id}", self.id);
+                workers.push(worker);
+            }
+            // The job's already queued, so it'll still get run by one of
+            // the workers we have.
+            Err(e) => warn!("Pool {} couldn't spawn an extra worker: {e}", self.id),
+        }
+    }
+
+    // Stop the last `n` workers and wait for them to exit, returning how
+    // many there were.
+    fn remove_workers(&self, n: usize) -> usize {
+        let retired: Vec<Worker> = {
+            let mut workers = self.workers.lock().unwrap();
+            let keep = workers.len().saturating_sub(n);
+            workers.drain(keep..).collect()
+        };
+
+        for worker in &retired {
+            worker.retire.store(true, Ordering::Release);
+        }
+
+        self.queue.wake_all();
+
+        let removed = retired.len();
+
+        for worker in retired {
+            if let Some(thread) = worker.thread {
+                join_worker(self.id, worker.id, thread);
+            }
+        }
+
+        removed
+    }
+
+    // Tell the newest worker that isn't already on its way out to stop,
+    // without waiting for it. It finishes the job it's on, if any, and takes
+    // itself out of the list as it exits, so until then shutting down still
+    // waits for it. Returns false if there was no such worker.
+    fn retire_worker(&self) -> bool {
+        let workers = self.workers.lock().unwrap();
+        let worker = workers.iter().rev().find(|worker| !worker.retiring());
+
+        match worker {
+            Some(worker) => {
+                worker.retire.store(true, Ordering::Release);
+                self.queue.wake_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Take an extra worker that's been idle for too long out of the pool.
+    // Returns false if the pool is shutting down, in which case the worker
+    // should stay put and exit along with the rest, or if a job has turned
+    // up since it timed out, in which case it should go and run it.
+    fn retire_extra(&self, id: usize) -> bool {
+        let mut workers = self.workers.lock().unwrap();
+
+        if self.queue.is_closed() || self.queue.backlog() > 0 {
+            return false;
+        }
+
+        // Dropping our own handle just detaches the thread, which is about
+        // to exit anyway.
+        workers.retain(|worker| worker.id != id);
+        true
+    }
+
+    // Count a job that's about to go in the queue. This has to happen before
+    // it goes in, or a quick worker could take it off the queue before we've
+    // added it and underflow the counters.
+    fn count_job(&self) {
+        *self.outstanding.lock().unwrap() += 1;
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_queued.fetch_max(queued, Ordering::Relaxed);
+    }
+
+    // Put the next round of something that keeps going until the pool shuts
+    // down on the timer, `delay` from now. This only fails once the pool is
+    // shutting down, which is when we want it to stop anyway.
+    fn reschedule(&self, delay: Duration, action: Action) {
+        let _ = self.timer.schedule(Instant::now() + delay, action);
+    }
+
+    // Spawn the timer thread, unless it's already running.
+    fn start_timer(this: &Arc<Shared>) -> io::Result<()> {
+        let mut timer_thread = this.timer_thread.lock().unwrap();
+
+        if timer_thread.is_none() {
+            let shared = Arc::clone(this);
+            let builder = thread::Builder::new().name(format!("{}-timer", this.config.name_prefix));
+
+            *timer_thread = Some(builder.spawn(move || {
+                while let Some(action) = shared.timer.next_due() {
+                    match action {
+                        // This only fails once the pool is shutting down,
+                        // and then the job would have been dropped anyway.
+                        Action::Enqueue(job) => {
+                            let _ = shared.submit(Priority::Normal, job, PushMode::Force);
+                        }
+                        Action::Inline(f) => f(),
+                    }
+                }
+            })?);
+        }
+
+        Ok(())
+    }
+
+    // Swap every worker whose thread has exited for a new one. Workers only
+    // exit by themselves when something's gone wrong: retired ones are
+    // taken out of `workers` before their threads finish, and shutdown
+    // closes the queue.
+    fn replace_dead_workers(this: &Arc<Shared>) {
+        let mut workers = this.workers.lock().unwrap();
+
+        if this.queue.is_closed() {
+            return;
+        }
+
+        for worker in workers.iter_mut() {
+            if !worker
+                .thread
+                .as_ref()
+                .is_some_and(thread::JoinHandle::is_finished)
+            {
+                continue;
+            }
+
+            let id = this.next_id.fetch_add(1, Ordering::Relaxed);
+
+            // If this fails we leave the dead one where it is and try
+            // again next time round.
+            let replacement = match Worker::new(id, Arc::clone(this), worker.extra) {
+                Ok(replacement) => replacement,
+                Err(e) => {
+                    warn!(
+                        "Couldn't replace pool {} worker {}: {e}",
+                        this.id, worker.id
+                    );
+                    continue;
+                }
+            };
+
+            warn!(
+                "Pool {} worker {} died; replacing it with worker {id}",
+                this.id, worker.id
+            );
+            let dead = mem::replace(worker, replacement);
+
+            // It's already finished, so this doesn't wait; it just gets us
+            // the panic, if that's what took it down.
+            if let Some(Err(payload)) = dead.thread.map(thread::JoinHandle::join) {
+                warn!(
+                    "Pool {} worker {} panicked: {}",
+                    this.id,
+                    dead.id,
+                    panic_message(payload.as_ref())
+                );
+            }
+        }
+    }
+
+    // Run a job that worker `id` has taken off the queue, keeping count of
+    // it in the pool's stats and the worker's own `completed`.
+    fn run_job(&self, id: usize, queued: Queued, completed: &AtomicUsize) {
+        let pool = self.id;
+        self.record_wait(queued.enqueued.elapsed());
+
+        debug!("Pool {pool} worker {id} got a job; executing.");
+        let mut guard = JobGuard::new(self, completed);
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+
+        // A panicking job shouldn't take the whole worker down with it,
+        // otherwise the pool quietly shrinks every time one goes wrong.
+        // Nothing the job touched is shared with the worker, so it's fine
+        // to assert unwind safety here. The panic is caught, so a job that
+        // panics is still timed.
+        let observer = self.config.observer.as_deref();
+
+        if let Some(observer) = observer {
+            self.call_hook("observer", || observer.on_job_start(id));
+        }
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "worker.job",
+            pool.id = pool,
+            worker.id = id,
+            duration_ms = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let entered = span.enter();
+
+        let started = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(queued.job));
+        let duration = started.elapsed();
+        self.durations.record(duration);
+
+        #[cfg(feature = "tracing")]
+        {
+            span.record("duration_ms", duration.as_millis());
+            drop(entered);
+        }
+        debug!(
+            "Pool {pool} worker {id} finished a job; duration_ms={}",
+            duration.as_millis()
+        );
+
+        if let Err(payload) = &result {
+            guard.panicked = true;
+
+            if let Some(observer) = observer {
+                self.call_hook("observer", || observer.on_job_panic(id, payload.as_ref()));
+            }
+
+            // Clone the handler out so we're not holding the lock while it
+            // runs.
+            let handler = self.panic_handler.read().unwrap().clone();
+
+            match handler {
+                Some(handler) => self.call_hook("panic handler", || handler(payload.as_ref())),
+                None => warn!(
+                    "Pool {pool} worker {id} job panicked: {}",
+                    panic_message(payload.as_ref())
+                ),
+            }
+        }
+
+        if let Some(observer) = observer {
+            self.call_hook("observer", || observer.on_job_end(id, duration));
+        }
+    }
+
+    fn record_wait(&self, wait: Duration) {
+        let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+        self.wait_micros.fetch_add(micros, Ordering::Relaxed);
+        self.waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Drop jobs that were taken out of the queue without running, and
+    // return how many there were.
+    fn discard(&self, jobs: Vec<Job>) -> usize {
+        let discarded = jobs.len();
+        self.uncount(discarded);
+
+        // Dropping them could run anything, so that happens last, once the
+        // counts are straight.
+        drop(jobs);
+        discarded
+    }
+
+    // Take `jobs` that came back out of the queue off the counts, as if
+    // they'd never gone in.
+    fn uncount(&self, jobs: usize) {
+        if jobs > 0 {
+            self.queued.fetch_sub(jobs, Ordering::Relaxed);
+            self.finish_outstanding(jobs);
+        }
+    }
+
+    // Hand a job to worker `id` alone. Apart from that, this is `submit`.
+    // A worker that's been told to stop doesn't take any more, since it
+    // would only hand them back to the shared queue on its way out.
+    fn submit_to<F>(&self, id: usize, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Holding the lock until the job's in means nobody can retire the
+        // worker in between.
+        let workers = self.workers.lock().unwrap();
+        let taking_jobs = workers
+            .iter()
+            .any(|worker| worker.id == id && !worker.retiring());
+
+        if !taking_jobs && !self.queue.is_closed() {
+            return Err(ExecuteError::UnknownWorker(f));
+        }
+
+        self.queue
+            .push_to(id, f, || self.count_job())
+            .map_err(ExecuteError::from)
+    }
+
+    // Take `jobs` off the `join` count, waking anyone waiting if that was
+    // the last of them.
+    fn finish_outstanding(&self, jobs: usize) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        *outstanding -= jobs;
+
+        if *outstanding > 0 {
+            return;
+        }
+
+        self.drained.notify_all();
+        drop(outstanding);
+
+        let handler = self.idle_handler.read().unwrap().clone();
+
+        if let Some(handler) = handler {
+            self.call_hook("idle callback", || handler());
+        }
+    }
+
+    // Call one of the user's hooks, and if it panics, log that and carry on.
+    // Hooks run on a worker, in the middle of keeping count of a job, or on
+    // the timer thread, and a panic getting any further would take the
+    // thread down and leave the counts wrong, so `join` never returns.
+    fn call_hook(&self, what: &str, hook: impl FnOnce()) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(hook)) {
+            warn!(
+                "Pool {} {what} panicked: {}",
+                self.id,
+                panic_message(payload.as_ref())
+            );
+        }
+    }
+
+    // Shared by `shutdown` and `Drop`. Closing the queue lets each worker
+    // drain what's left in it and then exit, so by the time the joins return
+    // every job has run. Calling it twice is harmless.
+    fn shutdown(&self) {
+        // Stop the timer first, so it isn't trying to hand jobs to a queue
+        // that's already closed.
+        self.timer.close();
+
+        if let Some(thread) = self.timer_thread.lock().unwrap().take() {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+
+        self.queue.close();
+
+        // Take the handles out first so we aren't holding the lock while we
+        // wait on the joins.
+        let threads: Vec<_> = self
+            .workers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|worker| Some((worker.id, worker.thread.take()?)))
+            .collect();
+
+        for (id, thread) in threads {
+            join_worker(self.id, id, thread);
+        }
+    }
+
+    fn shutdown_timeout(&self, timeout: Duration) -> ShutdownResult {
+        self.queue.close();
+
+        let deadline = Instant::now() + timeout;
+        let mut lock = self.exit_lock.lock().unwrap();
+
+        while self.alive.load(Ordering::Acquire) > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            lock = self.exited.wait_timeout(lock, deadline - now).unwrap().0;
+        }
+
+        let unfinished = self.alive.load(Ordering::Acquire);
+        drop(lock);
+
+        // Anything still running gets detached by dropping its handle, so
+        // that `shutdown` doesn't turn around and wait on it forever.
+        if unfinished > 0 {
+            for worker in self.workers.lock().unwrap().iter_mut() {
+                if worker.thread.as_ref().is_some_and(|t| !t.is_finished()) {
+                    worker.thread = None;
+                }
+            }
+        }
+
+        self.shutdown();
+
+        if unfinished == 0 {
+            ShutdownResult::Complete
+        } else {
+            ShutdownResult::TimedOut { unfinished }
+        }
+    }
+}
+
+/// Configures and creates a [`ThreadPool`].
+///
+/// Anything left unset falls back to a sensible default, so
+/// `ThreadPoolBuilder::new().build()` gives you one thread per core.
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    size: Option<usize>,
+    default_size: Option<usize>,
+    max_size: Option<usize>,
+    keep_alive: Option<Duration>,
+    thread_name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    rejection_policy: RejectionPolicy,
+    distribution: Distribution,
+    queue_order: QueueOrder,
+    pin_to_cores: bool,
+    thread_priority: ThreadPriority,
+    worker_init: Option<Arc<WorkerInit>>,
+    maintenance: Option<(Duration, Arc<Maintenance>)>,
+    observer: Option<Arc<dyn PoolObserver>>,
+    supervise: Option<Duration>,
+    starvation_threshold: Option<Duration>,
+    autoscale: Option<(usize, usize)>,
+    scale_up_at: Option<usize>,
+    scale_down_at: Option<usize>,
+    scale_cooldown: Option<Duration>,
+}
+
+type WorkerInit = dyn Fn(usize) + Send + Sync + 'static;
+type Maintenance = dyn Fn(usize) + Send + Sync + 'static;
+
+// The init closure can't be printed, so we just say whether there is one.
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("size", &self.size)
+            .field("default_size", &self.default_size)
+            .field("max_size", &self.max_size)
+            .field("keep_alive", &self.keep_alive)
+            .field("thread_name_prefix", &self.thread_name_prefix)
+            .field("stack_size", &self.stack_size)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("rejection_policy", &self.rejection_policy)
+            .field("distribution", &self.distribution)
+            .field("queue_order", &self.queue_order)
+            .field("pin_to_cores", &self.pin_to_cores)
+            .field("thread_priority", &self.thread_priority)
+            .field("worker_init", &self.worker_init.is_some())
+            .field(
+                "maintenance",
+                &self.maintenance.as_ref().map(|(interval, _)| interval),
+            )
+            .field("observer", &self.observer.is_some())
+            .field("supervise", &self.supervise)
+            .field("starvation_threshold", &self.starvation_threshold)
+            .field("autoscale", &self.autoscale)
+            .field("scale_up_at", &self.scale_up_at)
+            .field("scale_down_at", &self.scale_down_at)
+            .field("scale_cooldown", &self.scale_cooldown)
+            .finish()
+    }
 }
 
-// We'll note here that the job is _just_ the function
-// that we want to pass to the worker. There's nothing
-// super fancy here.
-type Job = Box<dyn FnOnce() + Send + 'static>;
+impl ThreadPoolBuilder {
+    /// Start a builder with every option at its default.
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
 
-#[derive(Debug)]
-pub struct PoolCreationError;
+    /// Set the number of threads in the pool.
+    ///
+    /// Defaults to `std::thread::available_parallelism()`, or if that
+    /// can't be worked out, [`ThreadPoolBuilder::default_size`].
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.size = Some(size);
+        self
+    }
 
-impl ThreadPool {
-    /// Create a new ThreadPool.
+    /// The number of threads to fall back on if the pool isn't given a
+    /// [`ThreadPoolBuilder::size`] and `std::thread::available_parallelism()`
+    /// fails, which it can on some platforms or in sandboxes that hide the
+    /// CPU count.
     ///
-    /// The size is the number of threads in the pool.
+    /// Defaults to 1. Like an explicit size, zero makes
+    /// [`ThreadPoolBuilder::build`] fail with `PoolCreationError::ZeroSize`,
+    /// but only when the fallback is actually used.
+    pub fn default_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.default_size = Some(size);
+        self
+    }
+
+    /// Let the pool grow past its [`ThreadPoolBuilder::size`], up to `max`
+    /// threads, while it's busy.
     ///
-    /// # Panics
+    /// The pool starts with `size` workers as usual. Whenever a job is
+    /// submitted with every worker busy, it spawns another one, until there
+    /// are `max`. These extra workers exit again once they've been idle for
+    /// [`ThreadPoolBuilder::keep_alive`]. A `max` no bigger than `size`
+    /// leaves the pool a fixed size, which is the default.
+    pub fn max_size(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_size = Some(max);
+        self
+    }
+
+    /// How long a worker spawned above the pool's
+    /// [`ThreadPoolBuilder::size`] can go without a job before it exits,
+    /// shrinking the pool back towards its size. Defaults to a minute.
     ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+    /// The pool's first `size` workers never exit this way. Only matters
+    /// along with [`ThreadPoolBuilder::max_size`].
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Name the worker threads `{prefix}-{id}`.
+    ///
+    /// Defaults to `rustchat-worker`, so the threads show up as
+    /// `rustchat-worker-0`, `rustchat-worker-1` and so on in debuggers and
+    /// crash dumps.
+    pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Set the stack size of each worker thread, in bytes.
+    ///
+    /// Defaults to whatever `std::thread` picks, which is 2 MiB on most
+    /// platforms. The OS may round this up to a page boundary or its own
+    /// minimum.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Limit how many jobs can wait in the queue at once.
+    ///
+    /// Once the queue is full, [`ThreadPool::execute`] blocks until a worker
+    /// frees up some room and [`ThreadPool::try_execute_now`] gives the job
+    /// back. A capacity of zero makes every submission a direct hand-off to
+    /// a waiting worker. Delayed and periodic jobs aren't held back when
+    /// they come due. Defaults to unbounded.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Choose what [`ThreadPool::execute`] does when the bounded queue is
+    /// full.
+    ///
+    /// Defaults to [`RejectionPolicy::Block`]. Has no effect without a
+    /// [`ThreadPoolBuilder::queue_capacity`].
+    pub fn rejection_policy(mut self, policy: RejectionPolicy) -> ThreadPoolBuilder {
+        self.rejection_policy = policy;
+        self
+    }
+
+    /// Choose how jobs are shared out between the workers.
+    ///
+    /// Defaults to [`Distribution::Shared`].
+    pub fn distribution(mut self, distribution: Distribution) -> ThreadPoolBuilder {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Choose whether workers take the oldest or the newest waiting job
+    /// first.
+    ///
+    /// Defaults to [`QueueOrder::Fifo`]. Jobs sent to one worker in
+    /// particular, including everything under
+    /// [`Distribution::RoundRobin`], always run oldest first.
+    pub fn queue_order(mut self, order: QueueOrder) -> ThreadPoolBuilder {
+        self.queue_order = order;
+        self
+    }
+
+    /// Pin each worker thread to its own core.
+    ///
+    /// Worker `id` goes on core `id % cores`, where `cores` is
+    /// `std::thread::available_parallelism()`. This needs the `affinity`
+    /// feature; without it, on a platform that can't pin threads, or if the
+    /// OS refuses, the workers just run unpinned. Defaults to off.
+    pub fn pin_to_cores(mut self, pin: bool) -> ThreadPoolBuilder {
+        self.pin_to_cores = pin;
+        self
+    }
+
+    /// Set the OS scheduling priority of each worker thread as it starts.
+    ///
+    /// `Low` and `High` ask for the lowest and highest priority a normal
+    /// thread can have; on Linux, that's the thread's nice value. This
+    /// needs the `priority` feature; without it, or if the OS refuses
+    /// (raising the priority usually needs privileges), the workers just
+    /// run at the normal priority. Defaults to [`ThreadPriority::Normal`].
+    pub fn thread_priority(mut self, priority: ThreadPriority) -> ThreadPoolBuilder {
+        self.thread_priority = priority;
+        self
+    }
+
+    /// Run `init` once on each worker thread, before it takes any jobs.
+    ///
+    /// It's called with the worker's id, which makes it a good place to set
+    /// up thread-locals or anything else that should happen once per thread
+    /// rather than once per job. Workers added later with
+    /// [`ThreadPool::add_workers`] run it too. If it panics, that worker
+    /// exits without taking any jobs.
+    pub fn worker_init(
+        mut self,
+        init: impl Fn(usize) + Send + Sync + 'static,
+    ) -> ThreadPoolBuilder {
+        self.worker_init = Some(Arc::new(init));
+        self
+    }
+
+    /// Have each worker call `maintenance` whenever it's been idle for
+    /// `interval`.
+    ///
+    /// It's called with the worker's id, on the worker's own thread, so it
+    /// can look after thread-local state: flushing a buffer, say, or
+    /// dropping a connection that's gone unused. A worker that stays idle
+    /// calls it again every `interval`; one that's kept busy never does. If
+    /// it panics, that's logged and the worker carries on.
+    pub fn maintenance(
+        mut self,
+        interval: Duration,
+        maintenance: impl Fn(usize) + Send + Sync + 'static,
+    ) -> ThreadPoolBuilder {
+        self.maintenance = Some((interval, Arc::new(maintenance)));
+        self
+    }
+
+    /// Have the workers call `observer` whenever a job starts, finishes or
+    /// panics. See [`PoolObserver`].
+    pub fn observer(mut self, observer: impl PoolObserver + 'static) -> ThreadPoolBuilder {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Check on the workers every `interval`, and replace any that have
+    /// died.
+    ///
+    /// Jobs can't take a worker down, but a panicking
+    /// [`ThreadPoolBuilder::worker_init`] can, and then the pool is left a
+    /// worker short. With this set, the pool's timer thread spots workers
+    /// that have exited on their own and spawns new ones, with fresh ids, in
+    /// their place. Defaults to off.
+    pub fn supervise(mut self, interval: Duration) -> ThreadPoolBuilder {
+        self.supervise = Some(interval);
+        self
+    }
+
+    /// Warn when a job has been waiting in the queue for longer than
+    /// `threshold`, which usually means every worker is stuck.
+    ///
+    /// The pool's timer thread looks at the oldest waiting job every so
+    /// often, and when it's been there too long, calls the
+    /// [`ThreadPool::on_starvation`] callback, or logs a warning if there
+    /// isn't one. Each job is only reported once, however long it goes on
+    /// waiting. Jobs held up by [`ThreadPool::pause`] don't count. Defaults
+    /// to off.
+    ///
+    /// ```
+    /// use std::{sync::mpsc, thread, time::Duration};
+    /// use rustchat::ThreadPoolBuilder;
+    ///
+    /// let pool = ThreadPoolBuilder::new()
+    ///     .size(1)
+    ///     .starvation_threshold(Duration::from_millis(100))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let (tx, rx) = mpsc::channel();
+    /// pool.on_starvation(move |waited| tx.send(waited).unwrap());
+    ///
+    /// // Tie up the only worker, so everything behind it has to wait.
+    /// pool.execute(|| thread::sleep(Duration::from_millis(500)));
+    /// for _ in 0..3 {
+    ///     pool.execute(|| {});
+    /// }
+    ///
+    /// let waited = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    /// assert!(waited >= Duration::from_millis(100));
+    /// ```
+    pub fn starvation_threshold(mut self, threshold: Duration) -> ThreadPoolBuilder {
+        self.starvation_threshold = Some(threshold);
+        self
+    }
+
+    /// Keep the number of workers between `min` and `max`, depending on how
+    /// many jobs are waiting.
+    ///
+    /// A monitor thread checks the queue every 100ms. Once at least
+    /// [`ThreadPoolBuilder::scale_up_at`] jobs are waiting, it adds a
+    /// worker; once no more than [`ThreadPoolBuilder::scale_down_at`] are
+    /// waiting and some worker is sitting idle, it removes one. It only
+    /// does one or the other every [`ThreadPoolBuilder::scale_cooldown`],
+    /// so a pool whose load hovers around a threshold doesn't keep spawning
+    /// and stopping threads. The pool starts out with its
+    /// [`ThreadPoolBuilder::size`], brought into range if need be.
+    ///
+    /// If `min` is zero or greater than `max`,
+    /// [`ThreadPoolBuilder::build`] fails with
+    /// `PoolCreationError::InvalidAutoscale`.
+    pub fn autoscale(mut self, min: usize, max: usize) -> ThreadPoolBuilder {
+        self.autoscale = Some((min, max));
+        self
+    }
+
+    /// How many jobs have to be waiting before an autoscaled pool adds a
+    /// worker. Defaults to 1, so it grows whenever a job has to wait.
+    pub fn scale_up_at(mut self, queued: usize) -> ThreadPoolBuilder {
+        self.scale_up_at = Some(queued);
+        self
+    }
+
+    /// How few jobs have to be waiting before an autoscaled pool removes an
+    /// idle worker. Defaults to 0. This should be below
+    /// [`ThreadPoolBuilder::scale_up_at`]; if it isn't, it's taken to be one
+    /// less.
+    pub fn scale_down_at(mut self, queued: usize) -> ThreadPoolBuilder {
+        self.scale_down_at = Some(queued);
+        self
+    }
+
+    /// How long an autoscaled pool waits after adding or removing a worker
+    /// before it does so again. Defaults to a second.
+    pub fn scale_cooldown(mut self, cooldown: Duration) -> ThreadPoolBuilder {
+        self.scale_cooldown = Some(cooldown);
+        self
+    }
+
+    // How many workers to start with, given what
+    // `thread::available_parallelism` said. Split out of `build` so tests
+    // can hand it the error case.
+    fn initial_size(&self, detected: io::Result<NonZeroUsize>) -> usize {
+        match self.size {
+            Some(size) => size,
+            None => match detected {
+                Ok(size) => size.get(),
+                Err(e) => {
+                    let size = self.default_size.unwrap_or(DEFAULT_SIZE);
+                    warn!("Couldn't tell how many threads to start ({e}); starting {size}");
+                    size
+                }
+            },
+        }
+    }
+
+    /// Spawn the workers and hand back the pool.
+    ///
+    /// A size of zero gives back `PoolCreationError::ZeroSize`, bad
+    /// [`ThreadPoolBuilder::autoscale`] bounds give back
+    /// `PoolCreationError::InvalidAutoscale`, and a failure to spawn any of
+    /// the worker threads is handed back as `PoolCreationError::Spawn`.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let mut size = self.initial_size(thread::available_parallelism());
+
+        if let Some((min, max)) = self.autoscale {
+            if min == 0 || min > max {
+                return Err(PoolCreationError::InvalidAutoscale { min, max });
+            }
+        }
+
+        let autoscale = self.autoscale.map(|(min, max)| {
+            size = size.clamp(min, max);
+            let scale_up_at = self.scale_up_at.unwrap_or(1);
+
+            Autoscale {
+                min,
+                max,
+                scale_up_at,
+                scale_down_at: self
+                    .scale_down_at
+                    .unwrap_or(0)
+                    .min(scale_up_at.saturating_sub(1)),
+                cooldown: self.scale_cooldown.unwrap_or(DEFAULT_SCALE_COOLDOWN),
+            }
+        });
+
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        let config = WorkerConfig {
+            name_prefix: self
+                .thread_name_prefix
+                .unwrap_or_else(|| DEFAULT_THREAD_NAME_PREFIX.to_string()),
+            stack_size: self.stack_size,
+            pin_to_cores: self.pin_to_cores,
+            priority: self.thread_priority,
+            init: self.worker_init,
+            maintenance: self.maintenance,
+            observer: self.observer,
+            keep_alive: self.keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE),
+        };
+
+        // The queue is the "manager" of the workers, because they're on
+        // multiple threads and we need a way to communicate with them. Each
+        // worker gets an `Arc` to the same one, and only one thread takes a
+        // job off it at a time.
+        let shared = Arc::new_cyclic(|me| Shared {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            me: me.clone(),
+            handle: OnceLock::new(),
+            queue: Queue::new(self.queue_capacity, self.queue_order),
+            rejection: self.rejection_policy,
+            distribution: self.distribution,
+            next_turn: AtomicUsize::new(0),
+            config,
+            workers: Mutex::new(Vec::with_capacity(size)),
+            max_workers: self.max_size.unwrap_or(size).max(size),
+            next_id: AtomicUsize::new(0),
+            timer: Timer::new(),
+            timer_thread: Mutex::new(None),
+            alive: AtomicUsize::new(0),
+            exit_lock: Mutex::new(()),
+            exited: Condvar::new(),
+            queued: AtomicUsize::new(0),
+            peak_queued: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            panicked: AtomicUsize::new(0),
+            durations: Histogram::new(),
+            wait_micros: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+            outstanding: Mutex::new(0),
+            drained: Condvar::new(),
+            panic_handler: RwLock::new(None),
+            idle_handler: RwLock::new(None),
+            starvation_handler: RwLock::new(None),
+        });
+
+        let pool = ThreadPool {
+            inner: Arc::new(Inner { shared }),
+        };
 
-        // we use the sender as the "manager" of the workers, because they're
-        // on multiple threads. We need a way to communicate with them.
-        let (sender, receiver) = mpsc::channel();
+        let _ = pool.inner.shared.handle.set(Arc::downgrade(&pool.inner));
 
-        // There's a lengthy explanation about why we chose to use the Arc<Mutex>> pattern
-        // here. Fundamentally, it comes down to the Multiple Producer, Single Consumer definition
-        // of channels in Rust. We only have one receiver and cannot simply clone it to have
-        // it run on multiple threads. Implementing it this way allows us to have a queue where
-        // only 1 thread reads from the queue at a time.
-        let receiver = Arc::new(Mutex::new(receiver));
+        // create some threads and store them. If one of them fails to spawn,
+        // dropping `pool` on the way out shuts down the workers we already
+        // made.
+        Shared::spawn_workers(&pool.inner.shared, size).map_err(PoolCreationError::Spawn)?;
 
-        let mut workers = Vec::with_capacity(size);
+        if let Some(interval) = self.supervise {
+            Shared::start_timer(&pool.inner.shared).map_err(PoolCreationError::Spawn)?;
+            supervise(Arc::clone(&pool.inner.shared), interval);
+        }
 
-        for id in 0..size {
-            // create some threads and store them
-            workers.push(Worker::new(id, Arc::clone(&receiver)))
+        if let Some(threshold) = self.starvation_threshold {
+            Shared::start_timer(&pool.inner.shared).map_err(PoolCreationError::Spawn)?;
+            watch_starvation(Arc::clone(&pool.inner.shared), threshold, None);
         }
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+
+        if let Some(autoscale) = autoscale {
+            let shared = Arc::clone(&pool.inner.shared);
+            let name = format!("{}-autoscale", shared.config.name_prefix);
+
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || autoscale.run(&shared))
+                .map_err(PoolCreationError::Spawn)?;
         }
+
+        Ok(pool)
     }
+}
 
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
+// `Job` is a boxed closure, so there's nothing useful to derive from. This
+// prints the size and whether jobs are still being taken instead.
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared = self.shared();
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        f.debug_struct("ThreadPool")
+            .field("workers", &self.len())
+            .field("accepting", &!shared.queue.is_closed())
+            .finish()
     }
+}
 
-    // pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
-    //     if size <= 0 {
-    //         return Err(PoolCreationError);
-    //     }
-    //     let mut threads = Vec::with_capacity(size);
-    //
-    //     for _ in 0..size {
-    //         // create some threads and store them
-    //     }
-    //     Ok(ThreadPool { threads })
-    // }
+/// A pool with one thread per core, as reported by
+/// `std::thread::available_parallelism`, or a single thread if that can't
+/// be worked out.
+///
+/// # Panics
+///
+/// Panics if a worker thread could not be spawned.
+impl Default for ThreadPool {
+    fn default() -> ThreadPool {
+        ThreadPoolBuilder::new().build().unwrap()
+    }
 }
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
+const DEFAULT_THREAD_NAME_PREFIX: &str = "rustchat-worker";
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+// How many workers a pool starts when it isn't told and can't tell how many
+// threads the machine can run, see `ThreadPoolBuilder::default_size`.
+const DEFAULT_SIZE: usize = 1;
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+// How often an autoscaled pool checks the queue, and how long it leaves
+// between changes by default.
+const AUTOSCALE_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_SCALE_COOLDOWN: Duration = Duration::from_secs(1);
+
+// The id the next pool gets, see `ThreadPool::id`.
+static NEXT_POOL_ID: AtomicU64 = AtomicU64::new(0);
+
+// How often a pool with a shutdown signal checks it.
+const SHUTDOWN_SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The most often a pool looks for starving jobs, however low its threshold.
+const STARVATION_MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long a worker above the pool's size can sit idle before it exits.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+// The settings every worker thread gets spawned with.
+struct WorkerConfig {
+    name_prefix: String,
+    stack_size: Option<usize>,
+    pin_to_cores: bool,
+    priority: ThreadPriority,
+    init: Option<Arc<WorkerInit>>,
+    maintenance: Option<(Duration, Arc<Maintenance>)>,
+    observer: Option<Arc<dyn PoolObserver>>,
+    keep_alive: Duration,
+}
+
+impl WorkerConfig {
+    fn thread_builder(&self, id: usize) -> thread::Builder {
+        let mut builder = thread::Builder::new().name(format!("{}-{id}", self.name_prefix));
+
+        if let Some(bytes) = self.stack_size {
+            builder = builder.stack_size(bytes);
         }
+
+        builder
     }
 }
 
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    // tells the thread to stop taking jobs, see `ThreadPool::remove_workers`
+    retire: Arc<AtomicBool>,
+    // jobs this worker has finished
+    completed: Arc<AtomicUsize>,
+    // spawned because the pool was busy, rather than one of its `size`
+    extra: bool,
+}
+
+impl fmt::Debug for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Worker")
+            .field("id", &self.id)
+            .field("joined", &self.thread.is_none())
+            .finish()
+    }
 }
 
 impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            // every thread will loop indefinitely and take a job off
-            // the receiver whenever there is one.
-            // Remember: There is only 1 receiver, so we need to lock
-            // the use of the receiver and make sure that we read the
-            // job off the queue -- this might lead to non-deterministic
-            // behaviour if one thread finishes before we exhaust the threadpool.
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down");
-                    break;
+    // Whether the worker's been told to stop, so it won't be taking any new
+    // jobs, though it may still be finishing one.
+    fn retiring(&self) -> bool {
+        self.retire.load(Ordering::Acquire)
+    }
+
+    pub fn new(id: usize, shared: Arc<Shared>, extra: bool) -> io::Result<Worker> {
+        // Count ourselves as alive before the thread starts, so that the pool
+        // never sees zero workers while they're still booting up.
+        shared.alive.fetch_add(1, Ordering::AcqRel);
+        shared.queue.register(id);
+        let guard = AliveGuard { shared, id };
+        let retire = Arc::new(AtomicBool::new(false));
+        let retired = Arc::clone(&retire);
+        let completed = Arc::new(AtomicUsize::new(0));
+        let worker_completed = Arc::clone(&completed);
+
+        let thread = guard.shared.config.thread_builder(id).spawn(move || {
+            let shared = &guard.shared;
+            let pool = shared.id;
+
+            if let Some(inner) = shared.handle.get() {
+                CURRENT.set(WeakThreadPool {
+                    inner: inner.clone(),
+                });
+            }
+
+            WORKER.set(Some(WorkerContext {
+                shared: Arc::clone(shared),
+                id,
+                completed: Arc::clone(&worker_completed),
+                yielding: Cell::new(false),
+            }));
+
+            if shared.config.pin_to_cores {
+                let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+                if !affinity::pin_current_thread(id % cores) {
+                    debug!("Pool {pool} worker {id} couldn't be pinned; running unpinned");
                 }
             }
-        });
 
-        Worker {
+            if !priority::set_current_thread_priority(shared.config.priority) {
+                debug!(
+                    "Pool {pool} worker {id} couldn't change its priority; running at the default"
+                );
+            }
+
+            if let Some(init) = &shared.config.init {
+                init(id);
+            }
+
+            // every thread will loop until the queue is closed and empty
+            // (or it's told to retire), taking a job off it whenever there
+            // is one.
+            // Remember: the queue sits behind a single lock, so only one
+            // worker reads from it at a time -- which worker ends up with
+            // which job is non-deterministic.
+            let maintenance = shared.config.maintenance.as_ref();
+            let keep_alive = extra.then_some(shared.config.keep_alive);
+
+            // Wake up often enough for whichever of the two comes first.
+            let timeout = maintenance
+                .map(|(interval, _)| *interval)
+                .into_iter()
+                .chain(keep_alive)
+                .min();
+
+            // since the last job finished, and since we last did anything
+            // at all, maintenance included
+            let mut idle_since = Instant::now();
+            let mut quiet_since = idle_since;
+
+            loop {
+                let queued = match shared.queue.pop(id, &retired, timeout) {
+                    Message::NewJob(queued) => queued,
+                    Message::TimedOut => {
+                        let expired =
+                            keep_alive.is_some_and(|keep_alive| idle_since.elapsed() >= keep_alive);
+
+                        if expired && shared.retire_extra(id) {
+                            info!("Pool {pool} worker {id} was idle for too long; shutting down");
+                            return;
+                        }
+
+                        if let Some((interval, maintenance)) = maintenance {
+                            if quiet_since.elapsed() >= *interval {
+                                let result =
+                                    panic::catch_unwind(AssertUnwindSafe(|| maintenance(id)));
+
+                                if let Err(payload) = result {
+                                    warn!(
+                                        "Pool {pool} worker {id} maintenance panicked: {}",
+                                        panic_message(payload.as_ref())
+                                    );
+                                }
+
+                                quiet_since = Instant::now();
+                            }
+                        }
+
+                        continue;
+                    }
+                    Message::Terminate => break,
+                };
+
+                shared.run_job(id, queued, &worker_completed);
+
+                idle_since = Instant::now();
+                quiet_since = idle_since;
+            }
+
+            if retired.load(Ordering::Acquire) {
+                // Workers retired by `remove_workers` are already out of
+                // the list, but a stand-in from `block_in_place`, or one
+                // the autoscaler let go, takes itself out, the same as an
+                // extra that's been idle too long.
+                shared
+                    .workers
+                    .lock()
+                    .unwrap()
+                    .retain(|worker| worker.id != id);
+                info!("Pool {pool} worker {id} was retired; shutting down");
+            } else {
+                info!("Pool {pool} worker {id} found the queue closed; shutting down");
+            }
+        })?;
+
+        Ok(Worker {
             id,
             thread: Some(thread),
+            retire,
+            completed,
+            extra,
+        })
+    }
+}
+
+// Queue up the next run of an `execute_every` job. Each run puts the one
+// after it on the timer, so there's only ever one waiting at a time.
+fn schedule_every(
+    shared: Arc<Shared>,
+    interval: Duration,
+    f: Arc<dyn Fn() + Send + Sync>,
+    handle: TaskHandle,
+) {
+    let pool = Arc::clone(&shared);
+
+    let job = move || {
+        if handle.is_cancelled() {
+            return;
+        }
+
+        // Reschedule even if this run panics, then let the panic carry on
+        // so the worker reports it.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f()));
+        schedule_every(shared, interval, f, handle);
+
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    };
+
+    pool.reschedule(interval, Action::Enqueue(Box::new(job)));
+}
+
+// Wait for a worker thread that's been told to stop.
+fn join_worker(pool: u64, id: usize, thread: thread::JoinHandle<()>) {
+    // If a job dropped the last handle, we're running on one of the workers,
+    // and it can't wait for itself to finish. It'll exit on its own once it
+    // gets back to the queue.
+    if thread.thread().id() == thread::current().id() {
+        return;
+    }
+
+    info!("Shutting down pool {pool} worker {id}");
+
+    // Jobs can't take a worker down any more, but the loop itself still
+    // could. Unwrapping here would panic inside `drop`, which aborts the
+    // process if we're already unwinding, so we just report it and carry on.
+    if let Err(payload) = thread.join() {
+        warn!(
+            "Pool {pool} worker {id} panicked: {}",
+            panic_message(payload.as_ref())
+        );
+    }
+}
+
+thread_local! {
+    // The pool a worker thread belongs to, for `ThreadPool::current`. Weak,
+    // so a worker doesn't keep its own pool from shutting down.
+    static CURRENT: RefCell<WeakThreadPool> = const {
+        RefCell::new(WeakThreadPool { inner: Weak::new() })
+    };
+
+    // Which worker this is, for `yield_now`.
+    static WORKER: RefCell<Option<WorkerContext>> = const { RefCell::new(None) };
+}
+
+struct WorkerContext {
+    shared: Arc<Shared>,
+    id: usize,
+    // the worker's own count of finished jobs
+    completed: Arc<AtomicUsize>,
+    // set while `yield_now` is running another job, so that one can't yield
+    // in turn
+    yielding: Cell<bool>,
+}
+
+/// Let the pool run one of its waiting jobs, from inside a job, before
+/// carrying on with this one.
+///
+/// A long job can call this every so often so that shorter ones queued up
+/// behind it aren't kept waiting until it's done, even on a small pool.
+/// The waiting job runs right here, on the same worker, and this returns
+/// once it's finished. It's taken the same way the worker would next take
+/// one, and counts in the pool's stats as usual. Returns whether there was
+/// a job to run; outside of a worker thread, there never is.
+///
+/// The other job runs in the middle of this one, nested on the same stack,
+/// so it mustn't wait on anything this one has yet to do, or neither will
+/// ever finish. A job that's running that way can't yield in turn: calling
+/// this from it does nothing and returns false, so each yield only ever
+/// goes one job deep. A job that wants to step aside altogether, letting
+/// everything else queued go first, is better off written as one that
+/// [`ThreadPool::execute_polling`] can pick up where it left off.
+///
+/// ```
+/// use std::{sync::{Arc, Mutex}, thread, time::Duration};
+/// use rustchat::ThreadPool;
+///
+/// let pool = ThreadPool::new(1);
+/// let log = Arc::new(Mutex::new(Vec::new()));
+///
+/// // Hold off until both are queued, so the short one's waiting.
+/// pool.pause();
+///
+/// let long = Arc::clone(&log);
+/// pool.execute(move || {
+///     for _ in 0..5 {
+///         thread::sleep(Duration::from_millis(20));
+///         rustchat::yield_now();
+///     }
+///     long.lock().unwrap().push("long");
+/// });
+///
+/// let short = Arc::clone(&log);
+/// pool.execute(move || short.lock().unwrap().push("short"));
+///
+/// pool.resume();
+/// pool.join().unwrap();
+///
+/// assert_eq!(*log.lock().unwrap(), ["short", "long"]);
+/// ```
+pub fn yield_now() -> bool {
+    WORKER.with_borrow(|worker| {
+        let Some(worker) = worker else {
+            return false;
+        };
+
+        if worker.yielding.get() {
+            return false;
+        }
+
+        let Some(queued) = worker.shared.queue.try_pop(worker.id) else {
+            return false;
+        };
+
+        // `run_job` catches the job panicking, so this always gets reset.
+        worker.yielding.set(true);
+        worker.shared.run_job(worker.id, queued, &worker.completed);
+        worker.yielding.set(false);
+        true
+    })
+}
+
+// The settings for `ThreadPoolBuilder::autoscale`.
+struct Autoscale {
+    min: usize,
+    max: usize,
+    scale_up_at: usize,
+    scale_down_at: usize,
+    cooldown: Duration,
+}
+
+impl Autoscale {
+    // The monitor thread's loop. It gets a thread of its own rather than
+    // running on the timer, so that spawning a worker doesn't hold up
+    // delayed jobs. It stops once the pool shuts down.
+    fn run(&self, shared: &Arc<Shared>) {
+        let mut last_change: Option<Instant> = None;
+
+        loop {
+            thread::sleep(AUTOSCALE_INTERVAL);
+
+            if shared.queue.is_closed() {
+                return;
+            }
+
+            if last_change.is_some_and(|at| at.elapsed() < self.cooldown) {
+                continue;
+            }
+
+            // Workers we've already let go may still be finishing a job, but
+            // they're not coming back, so they don't count.
+            let workers = shared
+                .workers
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|worker| !worker.retiring())
+                .count();
+            let queued = shared.queued.load(Ordering::Relaxed);
+            let active = shared.active.load(Ordering::Relaxed);
+
+            if queued >= self.scale_up_at && workers < self.max {
+                debug!(
+                    "Pool {} has {queued} jobs waiting; adding a worker",
+                    shared.id
+                );
+
+                if let Err(e) = Shared::spawn_workers(shared, 1) {
+                    warn!("Pool {} couldn't add a worker: {e}", shared.id);
+                }
+            } else if queued <= self.scale_down_at && active < workers && workers > self.min {
+                debug!(
+                    "Pool {} has {queued} jobs waiting; removing a worker",
+                    shared.id
+                );
+                // At least one worker is idle, but the one we let go might
+                // not be, so we don't wait for it to finish.
+                shared.retire_worker();
+            } else {
+                continue;
+            }
+
+            last_change = Some(Instant::now());
+        }
+    }
+}
+
+// Queue up the next check of a supervised pool. It runs on the timer thread
+// rather than as a job, so a pool that's busy (or out of workers) still gets
+// checked on time.
+fn supervise(shared: Arc<Shared>, interval: Duration) {
+    let pool = Arc::clone(&shared);
+
+    let check = move || {
+        Shared::replace_dead_workers(&shared);
+        supervise(shared, interval);
+    };
+
+    pool.reschedule(interval, Action::Inline(Box::new(check)));
+}
+
+// Queue up the next look for a job that's been waiting too long. Like
+// supervising, this runs on the timer thread, so it still happens when
+// every worker is stuck, which is the whole point. `reported` is when the
+// last job we warned about was queued, so we don't warn about it twice.
+fn watch_starvation(shared: Arc<Shared>, threshold: Duration, reported: Option<Instant>) {
+    let pool = Arc::clone(&shared);
+
+    let check = move || {
+        let mut reported = reported;
+
+        if let Some(enqueued) = shared.queue.oldest() {
+            let waited = enqueued.elapsed();
+
+            if waited >= threshold && reported != Some(enqueued) {
+                reported = Some(enqueued);
+                let handler = shared.starvation_handler.read().unwrap().clone();
+
+                match handler {
+                    Some(handler) => shared.call_hook("starvation callback", || handler(waited)),
+                    None => warn!(
+                        "Pool {} has had a job waiting for {}ms; are the workers stuck?",
+                        shared.id,
+                        waited.as_millis()
+                    ),
+                }
+            }
+        }
+
+        watch_starvation(shared, threshold, reported);
+    };
+
+    // Look often enough to notice not long after the threshold passes.
+    let interval = (threshold / 4).max(STARVATION_MIN_INTERVAL);
+
+    pool.reschedule(interval, Action::Inline(Box::new(check)));
+}
+
+// Queue up the next look at a pool's shutdown signal. Like supervising,
+// this runs on the timer thread, and the shutdown happens right there too.
+fn watch_shutdown_signal(shared: Arc<Shared>, token: CancelToken) {
+    let pool = Arc::clone(&shared);
+
+    let check = move || {
+        if token.is_cancelled() {
+            info!(
+                "Pool {} received its shutdown signal; shutting down",
+                shared.id
+            );
+            shared.shutdown();
+        } else {
+            watch_shutdown_signal(shared, token);
+        }
+    };
+
+    pool.reschedule(SHUTDOWN_SIGNAL_INTERVAL, Action::Inline(Box::new(check)));
+}
+
+// One attempt at an `execute_with_retry` job. If it panics with tries to
+// spare, it puts the next attempt on the queue instead of reporting it.
+fn retry_job(
+    shared: Arc<Shared>,
+    f: Arc<dyn Fn() + Send + Sync>,
+    attempt: usize,
+    max_attempts: usize,
+) -> impl FnOnce() + Send + 'static {
+    move || {
+        let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| f())) else {
+            return;
+        };
+
+        if attempt == max_attempts {
+            warn!(
+                "Pool {} job panicked on attempt {attempt} of {max_attempts}; giving up",
+                shared.id
+            );
+            panic::resume_unwind(payload);
+        }
+
+        debug!(
+            "Pool {} job panicked on attempt {attempt} of {max_attempts}; retrying",
+            shared.id
+        );
+
+        // We're on a worker, so this mustn't wait for room in the queue.
+        // It only fails once the pool is shutting down, and then the
+        // retry is dropped like anything else still waiting.
+        let next = retry_job(Arc::clone(&shared), f, attempt + 1, max_attempts);
+        let _ = shared.submit(Priority::Normal, next, PushMode::Force);
+    }
+}
+
+// One call of an `execute_polling` job. Unless it's done, it puts the next
+// call on the back of the queue.
+fn polling_job<F>(shared: Arc<Shared>, mut f: F) -> impl FnOnce() + Send + 'static
+where
+    F: FnMut() -> ControlFlow<()> + Send + 'static,
+{
+    move || loop {
+        if f().is_break() {
+            return;
+        }
+
+        // Once the pool is shutting down there's nowhere to put the job,
+        // so we see it through here instead.
+        if shared.queue.is_closed() {
+            continue;
+        }
+
+        // We're on a worker, so this mustn't wait for room in the queue.
+        // If the pool has started shutting down since we looked, the job
+        // comes straight back, and carries on here.
+        let next = polling_job(Arc::clone(&shared), f);
+
+        if let Err(e) = shared.submit(Priority::Normal, next, PushMode::Force) {
+            e.into_inner()();
+        }
+
+        return;
+    }
+}
+
+// Wrap a job that only makes sense on worker `id`, so that it's dropped
+// without running anywhere else. It ends up somewhere else if `id` goes
+// away without getting to it, since its inbox goes back in the shared queue
+// then.
+fn pinned<F>(id: usize, f: F) -> impl FnOnce() + Send + 'static
+where
+    F: FnOnce() + Send + 'static,
+{
+    move || {
+        if WORKER.with_borrow(|worker| worker.as_ref().is_some_and(|worker| worker.id == id)) {
+            f();
+        }
+    }
+}
+
+// Panic payloads are almost always a `&str` or a `String`, but `panic_any`
+// lets people throw whatever they want, so we can't count on it.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+// Marks a worker as gone when its thread exits, whether it returned normally
+// or a job panicked and took the thread down with it. If the spawn itself
+// fails the guard is dropped along with the closure, which undoes the count.
+// Anything left in the worker's inbox goes back in the shared queue, unless
+// the pool's shutting down, in which case it's thrown away.
+struct AliveGuard {
+    shared: Arc<Shared>,
+    id: usize,
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        let leftover = self.shared.queue.unregister(self.id);
+        self.shared.discard(leftover);
+
+        let _lock = self.shared.exit_lock.lock().unwrap();
+        self.shared.alive.fetch_sub(1, Ordering::AcqRel);
+        self.shared.exited.notify_all();
+    }
+}
+
+// Owned by a job that's called `block_in_place`. On the way out, it tells
+// the worker standing in for it, if there is one, to finish up and go.
+struct StandIn<'a> {
+    shared: &'a Shared,
+    retire: Option<Arc<AtomicBool>>,
+}
+
+impl Drop for StandIn<'_> {
+    fn drop(&mut self) {
+        if let Some(retire) = &self.retire {
+            retire.store(true, Ordering::Release);
+            self.shared.queue.wake_all();
+        }
+    }
+}
+
+// Counts a worker as busy for as long as the guard lives, then marks the job
+// as finished. This is in `Drop` so it still happens if the job panics.
+struct JobGuard<'a> {
+    shared: &'a Shared,
+    // the running worker's own count, see `ThreadPool::worker_stats`
+    worker_completed: &'a AtomicUsize,
+    // set by the worker once it's caught the job panicking
+    panicked: bool,
+}
+
+impl<'a> JobGuard<'a> {
+    fn new(shared: &'a Shared, worker_completed: &'a AtomicUsize) -> JobGuard<'a> {
+        shared.active.fetch_add(1, Ordering::Relaxed);
+        JobGuard {
+            shared,
+            worker_completed,
+            panicked: false,
+        }
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.shared.active.fetch_sub(1, Ordering::Relaxed);
+        self.shared.completed.fetch_add(1, Ordering::Relaxed);
+        self.worker_completed.fetch_add(1, Ordering::Relaxed);
+
+        // After `completed`, so `stats` never sees more panics than jobs.
+        if self.panicked {
+            self.shared.panicked.fetch_add(1, Ordering::Release);
         }
+
+        self.shared.finish_outstanding(1);
     }
 }
 
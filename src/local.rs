@@ -0,0 +1,169 @@
+use std::{cell::RefCell, collections::VecDeque, fmt, rc::Rc};
+
+/// A queue of jobs that don't have to be `Send`, because they never leave
+/// the thread that owns the queue.
+///
+/// A [`ThreadPool`](crate::ThreadPool) hands every job to another thread,
+/// so it can't take one that holds an `Rc` or anything else that's tied to
+/// the thread it was made on. This is for those jobs. It has no threads of
+/// its own: jobs wait in the queue until the owning thread calls
+/// [`LocalPool::run`] or [`LocalPool::try_run_one`], and then they run
+/// right there, one at a time, in the order they were handed over. That's
+/// the same deal as `futures::executor::LocalPool`.
+///
+/// Neither the pool nor its [`LocalSpawner`]s can be sent to another
+/// thread, so there's no way to get a job here from anywhere else. A job
+/// that panics carries on up through whichever call ran it, and the rest
+/// stay queued. Jobs still waiting when the pool is dropped are dropped
+/// without running.
+///
+/// ```
+/// use std::{cell::RefCell, rc::Rc};
+/// use rustchat::LocalPool;
+///
+/// let pool = LocalPool::new();
+/// let seen = Rc::new(RefCell::new(Vec::new()));
+///
+/// for i in 0..3 {
+///     let seen = Rc::clone(&seen);
+///     pool.execute(move || seen.borrow_mut().push(i));
+/// }
+///
+/// assert_eq!(pool.run(), 3);
+/// assert_eq!(*seen.borrow(), [0, 1, 2]);
+/// ```
+pub struct LocalPool {
+    spawner: LocalSpawner,
+}
+
+/// A handle that can hand jobs to a [`LocalPool`], from
+/// [`LocalPool::spawner`].
+///
+/// Jobs can take one of these along to queue up more work of their own.
+/// Like the pool, it can't leave the thread it was made on.
+#[derive(Clone)]
+pub struct LocalSpawner {
+    queue: Rc<RefCell<VecDeque<LocalJob>>>,
+}
+
+type LocalJob = Box<dyn FnOnce()>;
+
+impl LocalPool {
+    /// An empty pool.
+    pub fn new() -> LocalPool {
+        LocalPool {
+            spawner: LocalSpawner {
+                queue: Rc::default(),
+            },
+        }
+    }
+
+    /// A handle for handing jobs to this pool, say from inside one of its
+    /// jobs.
+    ///
+    /// ```
+    /// use std::{cell::Cell, rc::Rc};
+    /// use rustchat::LocalPool;
+    ///
+    /// let pool = LocalPool::new();
+    /// let spawner = pool.spawner();
+    /// let done = Rc::new(Cell::new(false));
+    ///
+    /// let flag = Rc::clone(&done);
+    /// pool.execute(move || spawner.execute(move || flag.set(true)));
+    ///
+    /// pool.run();
+    /// assert!(done.get());
+    /// ```
+    pub fn spawner(&self) -> LocalSpawner {
+        self.spawner.clone()
+    }
+
+    /// Queue up a job, to run the next time the pool is run.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + 'static,
+    {
+        self.spawner.execute(f);
+    }
+
+    /// Run jobs until there are none left, including any that get queued
+    /// along the way, and return how many ran.
+    pub fn run(&self) -> usize {
+        let mut ran = 0;
+
+        while self.try_run_one() {
+            ran += 1;
+        }
+
+        ran
+    }
+
+    /// Run the next job, if there is one. Returns whether there was.
+    pub fn try_run_one(&self) -> bool {
+        // Take the job out before running it, so the queue isn't borrowed
+        // while the job queues up more.
+        let job = self.spawner.queue.borrow_mut().pop_front();
+
+        match job {
+            Some(job) => {
+                job();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The number of jobs waiting to run.
+    pub fn len(&self) -> usize {
+        self.spawner.queue.borrow().len()
+    }
+
+    /// Whether there are no jobs waiting to run.
+    pub fn is_empty(&self) -> bool {
+        self.spawner.queue.borrow().is_empty()
+    }
+}
+
+impl Default for LocalPool {
+    fn default() -> LocalPool {
+        LocalPool::new()
+    }
+}
+
+impl LocalSpawner {
+    /// Queue up a job on the pool, to run the next time it's run.
+    ///
+    /// If the pool has been dropped, there's nothing left to run the job,
+    /// so it never does. It's dropped along with the last spawner.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + 'static,
+    {
+        self.queue.borrow_mut().push_back(Box::new(f));
+    }
+}
+
+// Whatever's still queued goes now, rather than whenever the last spawner
+// does, which might be one of the queued jobs itself.
+impl Drop for LocalPool {
+    fn drop(&mut self) {
+        let jobs = std::mem::take(&mut *self.spawner.queue.borrow_mut());
+        drop(jobs);
+    }
+}
+
+// The jobs can't be printed, so we just say how many there are.
+impl fmt::Debug for LocalPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalPool")
+            .field("queued", &self.len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for LocalSpawner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalSpawner").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,94 @@
+// The pool's own `debug!`, `info!` and `warn!`. With the `log` feature turned
+// on they go to the `log` crate, for whichever logger the application has
+// set up; without it they compile down to nothing, so the pool doesn't spam
+// anyone's output by default.
+//
+// Either way the arguments stay type-checked (and counted as used), which
+// saves a pile of `#[allow(unused)]` on variables that only show up in log
+// lines.
+
+#[cfg(feature = "log")]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        ::log::log!(::log::Level::$level, $($arg)*)
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!(Debug, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!(Info, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!(Warn, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "log"))]
+    #[test]
+    fn arguments_are_not_evaluated_without_the_feature() {
+        let mut evaluated = false;
+        debug!("{}", {
+            evaluated = true;
+            1
+        });
+
+        assert!(!evaluated);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn messages_go_to_the_installed_logger() {
+        use std::sync::Mutex;
+
+        use log::{Level, LevelFilter, Log, Metadata, Record};
+
+        use crate::ThreadPool;
+
+        // Every test shares the one logger, so this keeps everything and
+        // the assertions look for the lines they expect.
+        struct Captured(Mutex<Vec<(Level, String)>>);
+
+        impl Log for Captured {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record<'_>) {
+                let line = (record.level(), record.args().to_string());
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: Captured = Captured(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+
+        warn!("a warning from {}", "the test");
+
+        // Dropping the pool logs each worker shutting down.
+        let pool = ThreadPool::new(1);
+        let id = pool.id();
+        drop(pool);
+
+        let lines = LOGGER.0.lock().unwrap();
+        assert!(lines.contains(&(Level::Warn, "a warning from the test".to_owned())));
+        assert!(lines.iter().any(|(level, line)| *level == Level::Info
+            && line.starts_with(&format!("Pool {id} worker 0"))));
+    }
+}
@@ -3,7 +3,6 @@ use std::{
     fs,
     io::{prelude::*, BufReader},
     net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
@@ -21,23 +20,6 @@ fn main() {
     }
 }
 
-struct Message {
-    id: usize,
-    content: String,
-    author: Person,
-}
-
-struct Person {
-    id: usize,
-    name: String,
-}
-
-struct Room {
-    id: usize,
-    messages: Arc<Mutex<Vec<Message>>>,
-    people: Vec<Person>,
-}
-
 fn handle_connection(mut stream: TcpStream) {
     let buf_reader = BufReader::new(&mut stream);
     let request_line = buf_reader.lines().next().unwrap().unwrap();
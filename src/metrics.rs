@@ -0,0 +1,200 @@
+use std::fmt::Write;
+
+use crate::PoolStats;
+
+impl PoolStats {
+    /// Hand the snapshot to the `metrics` crate, for whichever recorder the
+    /// application has installed to pass on to its exporter.
+    ///
+    /// Worker, active and queued counts are set as gauges, and the job
+    /// totals as counters, with the same names as in
+    /// [`PoolStats::to_prometheus`]. The counters are set to the pool's
+    /// totals rather than added to, so this can be called as often as you
+    /// like, from a timer say, without counting anything twice.
+    pub fn record_metrics(&self) {
+        for (name, kind, _, value) in self.metrics() {
+            match kind {
+                Kind::Gauge => ::metrics::gauge!(name).set(value as f64),
+                Kind::Counter => ::metrics::counter!(name).absolute(value as u64),
+            }
+        }
+    }
+
+    /// Write the snapshot out in the Prometheus text exposition format, for
+    /// an exporter or a `/metrics` endpoint to serve, without going through
+    /// a `metrics` recorder.
+    ///
+    /// Worker, active and queued counts come out as gauges, and the job
+    /// totals as counters:
+    ///
+    /// ```
+    /// use rustchat::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// pool.execute(|| {});
+    /// pool.join().unwrap();
+    ///
+    /// let text = pool.stats().to_prometheus();
+    /// assert!(text.contains("pool_workers 2\n"));
+    /// assert!(text.contains("pool_completed_total 1\n"));
+    /// ```
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        for (name, kind, help, value) in self.metrics() {
+            let kind = match kind {
+                Kind::Gauge => "gauge",
+                Kind::Counter => "counter",
+            };
+
+            // Writing to a `String` can't fail.
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+
+    // Every value in the snapshot, with its name, kind and description.
+    fn metrics(&self) -> [(&'static str, Kind, &'static str, usize); 5] {
+        [
+            (
+                "pool_workers",
+                Kind::Gauge,
+                "Worker threads in the pool.",
+                self.workers,
+            ),
+            (
+                "pool_active",
+                Kind::Gauge,
+                "Workers that are running a job.",
+                self.active,
+            ),
+            (
+                "pool_queued",
+                Kind::Gauge,
+                "Jobs waiting for a free worker.",
+                self.queued,
+            ),
+            (
+                "pool_completed_total",
+                Kind::Counter,
+                "Jobs that have finished.",
+                self.completed,
+            ),
+            (
+                "pool_panicked_total",
+                Kind::Counter,
+                "Jobs that have panicked.",
+                self.panicked,
+            ),
+        ]
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use ::metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    use crate::PoolStats;
+
+    // Keeps the latest value of every gauge and counter, by name. Gauges
+    // store their `f64` as bits, the way the `metrics` atomics do.
+    #[derive(Default)]
+    struct Values(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+    impl Values {
+        fn handle(&self, key: &Key) -> Arc<AtomicU64> {
+            let mut values = self.0.lock().unwrap();
+            Arc::clone(values.entry(key.name().to_owned()).or_default())
+        }
+
+        fn get(&self, name: &str) -> u64 {
+            self.0.lock().unwrap()[name].load(Ordering::Relaxed)
+        }
+    }
+
+    impl Recorder for Values {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.handle(key))
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    const STATS: PoolStats = PoolStats {
+        workers: 4,
+        active: 1,
+        queued: 2,
+        completed: 30,
+        panicked: 3,
+    };
+
+    #[test]
+    fn snapshot_is_recorded_as_gauges_and_counters() {
+        let values = Values::default();
+        ::metrics::with_local_recorder(&values, || STATS.record_metrics());
+
+        let gauge = |name| f64::from_bits(values.get(name));
+        assert_eq!(gauge("pool_workers"), 4.0);
+        assert_eq!(gauge("pool_active"), 1.0);
+        assert_eq!(gauge("pool_queued"), 2.0);
+        assert_eq!(values.get("pool_completed_total"), 30);
+        assert_eq!(values.get("pool_panicked_total"), 3);
+
+        // Recording again sets the totals rather than adding to them.
+        ::metrics::with_local_recorder(&values, || STATS.record_metrics());
+        assert_eq!(values.get("pool_completed_total"), 30);
+    }
+
+    #[test]
+    fn prometheus_text_has_help_type_and_value_for_everything() {
+        assert_eq!(
+            STATS.to_prometheus(),
+            "\
+# HELP pool_workers Worker threads in the pool.
+# TYPE pool_workers gauge
+pool_workers 4
+# HELP pool_active Workers that are running a job.
+# TYPE pool_active gauge
+pool_active 1
+# HELP pool_queued Jobs waiting for a free worker.
+# TYPE pool_queued gauge
+pool_queued 2
+# HELP pool_completed_total Jobs that have finished.
+# TYPE pool_completed_total counter
+pool_completed_total 30
+# HELP pool_panicked_total Jobs that have panicked.
+# TYPE pool_panicked_total counter
+pool_panicked_total 3
+"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::{any::Any, time::Duration};
+
+/// Hooks the workers call around every job, for logging, metrics or tracing
+/// without building them into the pool.
+///
+/// Register one with
+/// [`ThreadPoolBuilder::observer`](crate::ThreadPoolBuilder::observer).
+/// Every method is called on the worker thread running the job, with that
+/// worker's id, and does nothing by default, so you only need to write the
+/// ones you care about. They're called for every job, so keep them quick.
+/// A hook that panics is logged and otherwise ignored, so a faulty observer
+/// can't take a worker down or stop the job from being counted.
+pub trait PoolObserver: Send + Sync {
+    /// A worker is about to run a job.
+    fn on_job_start(&self, worker_id: usize) {
+        let _ = worker_id;
+    }
+
+    /// A worker has finished a job, which took `duration`. This is called
+    /// for jobs that panicked too, after [`PoolObserver::on_job_panic`].
+    fn on_job_end(&self, worker_id: usize, duration: Duration) {
+        let _ = (worker_id, duration);
+    }
+
+    /// A job panicked, with `payload`. This is called before the pool's
+    /// panic handler, if it has one.
+    fn on_job_panic(&self, worker_id: usize, payload: &(dyn Any + Send)) {
+        let _ = (worker_id, payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any::Any,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::PoolObserver;
+    use crate::ThreadPoolBuilder;
+
+    #[derive(Default)]
+    struct Counts {
+        started: AtomicUsize,
+        ended: AtomicUsize,
+        panicked: AtomicUsize,
+    }
+
+    struct Counting(Arc<Counts>);
+
+    impl PoolObserver for Counting {
+        fn on_job_start(&self, _: usize) {
+            self.0.started.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_job_end(&self, _: usize, _: Duration) {
+            self.0.ended.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_job_panic(&self, _: usize, _: &(dyn Any + Send)) {
+            self.0.panicked.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Panicking;
+
+    impl PoolObserver for Panicking {
+        fn on_job_start(&self, _: usize) {
+            panic!("on_job_start");
+        }
+
+        fn on_job_end(&self, _: usize, _: Duration) {
+            panic!("on_job_end");
+        }
+
+        fn on_job_panic(&self, _: usize, _: &(dyn Any + Send)) {
+            panic!("on_job_panic");
+        }
+    }
+
+    #[test]
+    fn start_and_end_are_called_once_per_job() {
+        let counts = Arc::new(Counts::default());
+        let pool = ThreadPoolBuilder::new()
+            .size(2)
+            .observer(Counting(Arc::clone(&counts)))
+            .build()
+            .unwrap();
+        pool.set_panic_handler(|_| {});
+
+        for i in 0..10 {
+            pool.execute(move || assert!(i != 3));
+        }
+        pool.join().unwrap();
+
+        assert_eq!(counts.started.load(Ordering::SeqCst), 10);
+        assert_eq!(counts.ended.load(Ordering::SeqCst), 10);
+        assert_eq!(counts.panicked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panicking_observer_does_not_kill_the_worker() {
+        let pool = ThreadPoolBuilder::new()
+            .size(1)
+            .observer(Panicking)
+            .build()
+            .unwrap();
+        pool.set_panic_handler(|_| {});
+
+        pool.execute(|| panic!("boom"));
+        assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+        pool.join().unwrap();
+
+        assert_eq!(pool.completed_count(), 2);
+        assert_eq!(pool.panicked_count(), 1);
+    }
+}
@@ -0,0 +1,43 @@
+// Setting a worker thread's OS scheduling priority, through
+// `thread-priority`. Without the `priority` feature it quietly reports that
+// the priority wasn't changed.
+
+/// How the OS should schedule the worker threads, set with
+/// [`ThreadPoolBuilder::thread_priority`](crate::ThreadPoolBuilder::thread_priority).
+///
+/// Not to be confused with [`Priority`](crate::Priority), which orders jobs
+/// within the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ThreadPriority {
+    /// Background work that should give way to everything else.
+    Low,
+    /// Whatever the threads would get anyway.
+    #[default]
+    Normal,
+    /// Work that should go ahead of other threads. Raising a thread's
+    /// priority usually needs extra privileges.
+    High,
+}
+
+#[cfg(feature = "priority")]
+pub(crate) fn set_current_thread_priority(priority: ThreadPriority) -> bool {
+    use thread_priority::ThreadPriority as Os;
+
+    let priority = match priority {
+        ThreadPriority::Low => Os::Min,
+        ThreadPriority::Normal => return true,
+        ThreadPriority::High => Os::Max,
+    };
+
+    thread_priority::set_current_thread_priority(priority).is_ok()
+}
+
+#[cfg(not(feature = "priority"))]
+pub(crate) fn set_current_thread_priority(priority: ThreadPriority) -> bool {
+    priority == ThreadPriority::Normal
+}
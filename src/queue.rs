@@ -0,0 +1,579 @@
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::Job;
+
+/// How urgently a job should be picked up.
+///
+/// Workers always take the highest priority job that's waiting. Jobs with
+/// the same priority run in the order they were submitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// What the pool does with a job when its bounded queue is full.
+///
+/// Only matters for pools built with
+/// [`ThreadPoolBuilder::queue_capacity`](crate::ThreadPoolBuilder::queue_capacity);
+/// an unbounded queue is never full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum RejectionPolicy {
+    /// Block the submitting thread until there's room.
+    #[default]
+    Block,
+    /// Run the job right away on the submitting thread instead. This slows
+    /// down whoever is producing the work, which gives the workers a chance
+    /// to catch up.
+    CallerRuns,
+    /// Drop the job without running it.
+    Discard,
+}
+
+/// How jobs are shared out between the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Distribution {
+    /// Every worker takes the next job from one shared queue, so whoever's
+    /// free picks up the work. One worker can end up running a whole run of
+    /// short jobs while another is stuck on a long one, but no job waits
+    /// while there's a worker free.
+    #[default]
+    Shared,
+    /// Each job goes to the next worker in turn, and waits for that worker
+    /// even if another is free. That gives every worker the same share of
+    /// the jobs, at the cost of balancing the load. Jobs queued this way
+    /// don't count towards a bounded queue's capacity, and they run in the
+    /// order they were submitted whatever their [`Priority`].
+    RoundRobin,
+}
+
+/// Which of the waiting jobs a worker takes next.
+///
+/// Either way, a higher [`Priority`] still goes first; this only decides
+/// between jobs of the same priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum QueueOrder {
+    /// The oldest, so jobs run in the order they were submitted.
+    #[default]
+    Fifo,
+    /// The newest. Whatever the newest job works on is more likely to still
+    /// be in the cache, but the oldest jobs can be left waiting for as long
+    /// as new ones keep coming.
+    Lifo,
+}
+
+// The queue every worker pulls from.
+//
+// This used to be a `mpsc` channel with the receiver behind a mutex, but a
+// channel can only ever hand things out in the order they went in. Keeping a
+// FIFO per priority behind one lock lets workers skip ahead to urgent work
+// while still only taking the lock once per job.
+pub(crate) struct Queue {
+    state: Mutex<State>,
+    order: QueueOrder,
+    available: Condvar,
+    // `None` means unbounded
+    capacity: Option<usize>,
+    space: Condvar,
+}
+
+struct State {
+    // indexed by `Priority as usize`, so the first non-empty lane wins
+    lanes: [VecDeque<Queued>; 3],
+    len: usize,
+    // jobs meant for one worker in particular, keyed by worker id. These
+    // don't count towards `len`, so they skip the capacity limit.
+    inboxes: HashMap<usize, VecDeque<Queued>>,
+    // workers blocked in `pop` waiting for a job
+    idle: usize,
+    // while set, jobs stay put until the queue is resumed or closed
+    paused: bool,
+    closed: bool,
+}
+
+impl State {
+    // Whether a bounded queue can take another job. Idle workers are about
+    // to take a job each, so they count as room as well. That's also what
+    // makes a capacity of zero a hand-off: a job only goes in when there's a
+    // worker waiting for it. While paused, nobody's about to take
+    // anything, so idle workers don't count.
+    fn has_room(&self, capacity: usize) -> bool {
+        let idle = if self.paused { 0 } else { self.idle };
+        self.len < capacity + idle
+    }
+
+    // Take every job out, inboxes included.
+    fn take_all(&mut self) -> Vec<Job> {
+        let jobs = self
+            .lanes
+            .iter_mut()
+            .chain(self.inboxes.values_mut())
+            .flat_map(|lane| lane.drain(..))
+            .map(|queued| queued.job)
+            .collect();
+
+        self.len = 0;
+        jobs
+    }
+
+    // Take away worker `id`'s inbox and put whatever's still in it at the
+    // back of the normal lane, for the other workers to run. Returns how
+    // many jobs that was.
+    fn hand_back_inbox(&mut self, id: usize) -> usize {
+        let Some(inbox) = self.inboxes.remove(&id) else {
+            return 0;
+        };
+
+        let jobs = inbox.len();
+        self.lanes[Priority::Normal as usize].extend(inbox);
+        self.len += jobs;
+        jobs
+    }
+
+    // Whether workers may take jobs. Closing overrides a pause, so a paused
+    // pool can still be shut down.
+    fn dispatching(&self) -> bool {
+        !self.paused || self.closed
+    }
+}
+
+// A job along with when it went in the queue, so the worker can tell how
+// long it waited. The timestamp lives here, next to the box, rather than
+// in a closure wrapped around the job, so queueing a job costs exactly one
+// allocation.
+pub(crate) struct Queued {
+    pub(crate) job: Job,
+    pub(crate) enqueued: Instant,
+}
+
+impl Queued {
+    fn new<F>(f: F) -> Queued
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Queued {
+            job: into_job(f),
+            enqueued: Instant::now(),
+        }
+    }
+}
+
+// Box `f` up as a `Job`, unless it already is one. Plenty of jobs turn up
+// already boxed (from the timer, a scope, or `dyn Executor`), and boxing
+// them again would cost a second allocation, and a second indirect call
+// when they run.
+fn into_job<F>(f: F) -> Job
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut f = Some(f);
+
+    match (&mut f as &mut dyn Any).downcast_mut::<Option<Job>>() {
+        Some(job) => job.take().unwrap(),
+        None => Box::new(f.unwrap()),
+    }
+}
+
+// What a worker gets back from `pop`.
+pub(crate) enum Message {
+    NewJob(Queued),
+    // nothing turned up within the timeout given to `pop`
+    TimedOut,
+    // stop taking jobs and exit. Sent to every worker once the queue is
+    // closed and empty, and to a single worker when it's been retired.
+    Terminate,
+}
+
+// What `push` should do when a bounded queue is full.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PushMode {
+    // block the caller until there's room
+    Wait,
+    // give the job straight back
+    Fail,
+    // push it regardless. Used for delayed jobs, since the timer thread
+    // can't afford to sit blocked behind a busy pool.
+    Force,
+}
+
+pub(crate) enum PushError<F> {
+    Closed(F),
+    Full(F),
+    // `push_to` was given a worker that doesn't have an inbox
+    NoWorker(F),
+}
+
+impl Queue {
+    pub(crate) fn new(capacity: Option<usize>, order: QueueOrder) -> Queue {
+        Queue {
+            state: Mutex::new(State {
+                lanes: Default::default(),
+                len: 0,
+                inboxes: HashMap::new(),
+                idle: 0,
+                paused: false,
+                closed: false,
+            }),
+            order,
+            available: Condvar::new(),
+            capacity,
+            space: Condvar::new(),
+        }
+    }
+
+    // Jobs never run while the lock is held, so it's hard to poison, but if
+    // it ever were, every worker would panic on it one after another and
+    // take the pool down with them. The state is just a handful of queues
+    // and counters that are never left half-updated, so it's safe to carry
+    // on with it.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Add a job to the back of its lane, or give it back if the queue has
+    // been closed (or, depending on `mode`, is full).
+    //
+    // `on_push` runs with the lock held just before the job goes in, so the
+    // caller's bookkeeping happens before any worker can see the job.
+    pub(crate) fn push<F>(
+        &self,
+        priority: Priority,
+        f: F,
+        mode: PushMode,
+        on_push: impl FnOnce(),
+    ) -> Result<(), PushError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+
+        loop {
+            if state.closed {
+                return Err(PushError::Closed(f));
+            }
+
+            match self.capacity {
+                Some(capacity) if mode != PushMode::Force && !state.has_room(capacity) => {
+                    if mode == PushMode::Fail {
+                        return Err(PushError::Full(f));
+                    }
+
+                    state = self
+                        .space
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                _ => break,
+            }
+        }
+
+        on_push();
+        state.lanes[priority as usize].push_back(Queued::new(f));
+        state.len += 1;
+        self.available.notify_one();
+        Ok(())
+    }
+
+    // Add a job to the inbox of worker `id`, so that nobody else can take
+    // it. Apart from the capacity limit, this works like `push`.
+    pub(crate) fn push_to<F>(
+        &self,
+        id: usize,
+        f: F,
+        on_push: impl FnOnce(),
+    ) -> Result<(), PushError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+
+        if state.closed {
+            return Err(PushError::Closed(f));
+        }
+
+        let Some(inbox) = state.inboxes.get_mut(&id) else {
+            return Err(PushError::NoWorker(f));
+        };
+
+        on_push();
+        inbox.push_back(Queued::new(f));
+
+        // There's no telling which of the waiting workers is `id`.
+        self.available.notify_all();
+        Ok(())
+    }
+
+    // Give worker `id` an inbox, before it starts calling `pop`.
+    pub(crate) fn register(&self, id: usize) {
+        self.lock().inboxes.insert(id, VecDeque::new());
+    }
+
+    // Take away worker `id`'s inbox once it's stopped calling `pop`. Jobs
+    // it never got round to go in the shared queue for the other workers,
+    // unless the queue's been closed, in which case they're handed back for
+    // the caller to drop.
+    pub(crate) fn unregister(&self, id: usize) -> Vec<Job> {
+        let mut state = self.lock();
+
+        if state.closed {
+            let inbox = state.inboxes.remove(&id).unwrap_or_default();
+            return inbox.into_iter().map(|queued| queued.job).collect();
+        }
+
+        if state.hand_back_inbox(id) > 0 {
+            self.available.notify_all();
+        }
+
+        Vec::new()
+    }
+
+    // Block until there's a job for worker `id` to run, taking one from its
+    // inbox ahead of anything else. Once the queue is closed this keeps
+    // handing out what's left, then sends `Terminate` so the worker can
+    // exit. It also sends `Terminate` as soon as `retire` is set, once the
+    // inbox is empty, leaving the shared jobs for the other workers. If the
+    // queue is paused then, the inbox can't be emptied first, so what's in
+    // it goes back in the shared queue instead, the same as in `unregister`. With a `timeout`, it gives
+    // up with `TimedOut` once it's been idle that long.
+    pub(crate) fn pop(&self, id: usize, retire: &AtomicBool, timeout: Option<Duration>) -> Message {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.lock();
+
+        loop {
+            if state.dispatching() {
+                if let Some(job) = state.inboxes.get_mut(&id).and_then(VecDeque::pop_front) {
+                    return Message::NewJob(job);
+                }
+            }
+
+            if retire.load(Ordering::Acquire) {
+                if state.hand_back_inbox(id) > 0 {
+                    self.available.notify_all();
+                }
+
+                return Message::Terminate;
+            }
+
+            if state.dispatching() {
+                if let Some(job) = self.take_shared(&mut state) {
+                    return Message::NewJob(job);
+                }
+
+                if state.closed {
+                    return Message::Terminate;
+                }
+            }
+
+            let now = Instant::now();
+
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Message::TimedOut;
+            }
+
+            // Going idle makes room in a bounded queue, so let a blocked
+            // producer know.
+            state.idle += 1;
+            self.space.notify_one();
+
+            state = match deadline {
+                None => self
+                    .available
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    self.available
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+
+            state.idle -= 1;
+        }
+    }
+
+    // Take a job for worker `id` if there's one waiting, the same one `pop`
+    // would, but give back `None` rather than wait for one.
+    pub(crate) fn try_pop(&self, id: usize) -> Option<Queued> {
+        let mut state = self.lock();
+
+        if !state.dispatching() {
+            return None;
+        }
+
+        match state.inboxes.get_mut(&id).and_then(VecDeque::pop_front) {
+            Some(job) => Some(job),
+            None => self.take_shared(&mut state),
+        }
+    }
+
+    // Take the next job from the shared lanes, in the queue's order.
+    fn take_shared(&self, state: &mut State) -> Option<Queued> {
+        let take = match self.order {
+            QueueOrder::Fifo => VecDeque::pop_front,
+            QueueOrder::Lifo => VecDeque::pop_back,
+        };
+
+        let job = state.lanes.iter_mut().find_map(take)?;
+        state.len -= 1;
+        self.space.notify_one();
+        Some(job)
+    }
+
+    // Stop taking new jobs and wake every worker, so the idle ones notice,
+    // along with anyone blocked waiting for room.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+
+    // Take every job out of the queue, inboxes included, for the caller to
+    // drop once it's no longer holding the lock.
+    pub(crate) fn clear(&self) -> Vec<Job> {
+        let jobs = self.lock().take_all();
+        self.space.notify_all();
+        jobs
+    }
+
+    // `clear` and `close` in one go, so no worker can take a job in between.
+    pub(crate) fn close_and_clear(&self) -> Vec<Job> {
+        let jobs = {
+            let mut state = self.lock();
+            state.closed = true;
+            state.take_all()
+        };
+
+        self.available.notify_all();
+        self.space.notify_all();
+        jobs
+    }
+
+    // Stop or start handing out jobs. Resuming wakes every worker, since
+    // jobs may have piled up in the meantime, and anyone blocked waiting
+    // for room, since idle workers count as room again.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.lock().paused = paused;
+
+        if !paused {
+            self.available.notify_all();
+            self.space.notify_all();
+        }
+    }
+
+    // Wake every idle worker so they check their `retire` flag. Taking the
+    // lock means a worker that's just checked it can't miss this.
+    pub(crate) fn wake_all(&self) {
+        let _state = self.lock();
+        self.available.notify_all();
+    }
+
+    // How many of the queued jobs there's no idle worker ready to take.
+    pub(crate) fn backlog(&self) -> usize {
+        let state = self.lock();
+        state.len.saturating_sub(state.idle)
+    }
+
+    // When the job that's been waiting longest went in, or `None` if there
+    // isn't one. While the queue is paused, jobs are meant to wait, so
+    // there's nothing to report then either.
+    pub(crate) fn oldest(&self) -> Option<Instant> {
+        let state = self.lock();
+
+        if !state.dispatching() {
+            return None;
+        }
+
+        // Jobs only ever go in at the back, so the oldest in each lane is
+        // at the front, whichever end the workers take from.
+        state
+            .lanes
+            .iter()
+            .chain(state.inboxes.values())
+            .filter_map(|lane| lane.front())
+            .map(|queued| queued.enqueued)
+            .min()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::{into_job, Message, PushMode, Queue, QueueOrder};
+    use crate::{Job, Priority};
+
+    #[test]
+    fn poisoned_lock_is_shrugged_off() {
+        let queue = Queue::new(None, QueueOrder::Fifo);
+        queue.register(0);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _state = queue.state.lock().unwrap();
+            panic!("poison the queue");
+        }));
+        assert!(queue.state.is_poisoned());
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&ran);
+        let job = move || {
+            count.fetch_add(1, Ordering::Relaxed);
+        };
+        assert!(queue
+            .push(Priority::Normal, job, PushMode::Wait, || ())
+            .is_ok());
+
+        let Message::NewJob(queued) = queue.pop(0, &AtomicBool::new(false), None) else {
+            panic!("expected the job back");
+        };
+        (queued.job)();
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn boxed_jobs_are_not_boxed_again() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let job: Job = Box::new(move || {
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+        let before = &*job as *const _ as *const ();
+
+        let job = into_job(job);
+        assert_eq!(&*job as *const _ as *const (), before);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use crate::{Job, ThreadPool};
+
+/// A scope for running jobs that borrow from the stack, created with
+/// [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    data: Arc<ScopeData>,
+    // Invariant over both lifetimes, the same as `std::thread::Scope`, so
+    // the compiler can't shrink them to let a job outlive what it borrows.
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// Lives in an `Arc` rather than on the stack, because a job still touches it
+// for a moment after it's counted itself as done.
+struct ScopeData {
+    // jobs handed to the pool that haven't finished (or been dropped) yet
+    pending: Mutex<usize>,
+    done: Condvar,
+    panicked: AtomicBool,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Hand a job to the pool that can borrow anything that outlives the
+    /// scope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool can't take the job, the same as
+    /// [`ThreadPool::execute`].
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.data.pending.lock().unwrap() += 1;
+        let scoped = ScopedJob {
+            f,
+            guard: PendingGuard(Arc::clone(&self.data)),
+        };
+
+        let job = move || {
+            // Moved in whole, so a job that's dropped without running drops
+            // `f` before the guard, the same as one that runs.
+            let ScopedJob { f, guard } = scoped;
+
+            // Note the panic for `scope` to pass on, then let it carry on up
+            // to the worker so it gets reported like any other.
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                guard.0.panicked.store(true, Ordering::Relaxed);
+                panic::resume_unwind(payload);
+            }
+        };
+
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(job);
+
+        // SAFETY: `scope` doesn't return until every job's guard has been
+        // dropped, whether the job ran or was thrown away, and the guard
+        // always goes after `f`, so nothing the job borrows can go away
+        // while the pool still holds on to it.
+        let job: Job = unsafe { mem::transmute(job) };
+
+        self.pool.execute(job);
+    }
+}
+
+// A scoped job's closure along with its guard. Fields are dropped in the
+// order they're declared, so `f`, and everything it borrows, is gone by the
+// time the guard lets `scope` return. Captured straight into the job's
+// closure, they'd be dropped in whatever order the compiler picked.
+struct ScopedJob<F> {
+    f: F,
+    guard: PendingGuard,
+}
+
+// Counts a job as finished when it's dropped, which happens whether it ran,
+// panicked, or never got to run at all.
+struct PendingGuard(Arc<ScopeData>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap();
+        *pending -= 1;
+
+        if *pending == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+pub(crate) fn scope<'env, F, T>(pool: &ThreadPool, f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        pool,
+        data: Arc::new(ScopeData {
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            panicked: AtomicBool::new(false),
+        }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+
+    // Even if `f` panics we have to wait for the jobs it already started,
+    // since they may be borrowing from the frames that are about to unwind.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+    let mut pending = scope.data.pending.lock().unwrap();
+
+    while *pending > 0 {
+        pending = scope.data.done.wait(pending).unwrap();
+    }
+
+    drop(pending);
+
+    match result {
+        Err(payload) => panic::resume_unwind(payload),
+        Ok(_) if scope.data.panicked.load(Ordering::Relaxed) => {
+            panic!("a scoped job panicked")
+        }
+        Ok(value) => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use crate::ThreadPool;
+
+    // Sets its flag once it's completely done being dropped.
+    struct Borrower<'a> {
+        dropped: &'a AtomicBool,
+    }
+
+    impl Drop for Borrower<'_> {
+        fn drop(&mut self) {
+            thread::sleep(Duration::from_millis(100));
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn cleared_job_is_dropped_before_scope_returns() {
+        let pool = ThreadPool::new(1);
+        let dropped = AtomicBool::new(false);
+
+        pool.pause();
+
+        // Cleared from another thread, which the scope doesn't wait for, so
+        // only the job's guard keeps it from returning mid-drop.
+        let clearer = pool.scope(|s| {
+            let borrower = Borrower { dropped: &dropped };
+            s.execute(move || drop(borrower));
+
+            let pool = pool.clone();
+            thread::spawn(move || pool.clear_queue())
+        });
+
+        assert!(
+            dropped.load(Ordering::SeqCst),
+            "scope returned while a borrow was alive"
+        );
+        assert_eq!(clearer.join().unwrap(), 1);
+        pool.resume();
+    }
+
+    #[test]
+    fn jobs_can_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let mut totals = [0; 8];
+
+        pool.scope(|s| {
+            for (i, total) in totals.iter_mut().enumerate() {
+                s.execute(move || *total = i * 10);
+            }
+        });
+
+        assert_eq!(totals, [0, 10, 20, 30, 40, 50, 60, 70]);
+    }
+
+    #[test]
+    #[should_panic(expected = "a scoped job panicked")]
+    fn panicking_job_panics_scope() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_handler(|_| {});
+        pool.scope(|s| s.execute(|| panic!("boom")));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use futures_task::{FutureObj, Spawn, SpawnError};
+
+use crate::{queue::PushMode, Priority, Shared, ThreadPool};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+// A future that's been handed to the pool. Each poll is a normal job, and
+// waking the task just queues up another one.
+struct Task {
+    // `None` once the future has finished, or panicked
+    future: Mutex<Option<BoxFuture>>,
+    // whether there's already a poll waiting in the queue, so a burst of
+    // wake-ups only costs one job
+    scheduled: AtomicBool,
+    // not a `ThreadPool`, so a task that's never woken again can't keep the
+    // workers alive
+    shared: Arc<Shared>,
+}
+
+impl Task {
+    fn new<F>(pool: &ThreadPool, future: F) -> Arc<Task>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(true),
+            shared: Arc::clone(&pool.inner.shared),
+        })
+    }
+
+    fn run(self: Arc<Task>) {
+        // Clear this before polling, so a wake-up that comes in while we're
+        // busy queues another poll rather than getting lost.
+        self.scheduled.store(false, Ordering::Release);
+
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+
+        // If the poll queued above gets picked up while we're still here, it
+        // waits for us on this lock and then polls again.
+        let mut future = self.future.lock().unwrap();
+
+        let Some(inner) = future.as_mut() else {
+            return;
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll(&mut cx))) {
+            Ok(Poll::Pending) => {}
+            Ok(Poll::Ready(())) => *future = None,
+            Err(payload) => {
+                // Let go of the lock before carrying on, so it isn't
+                // poisoned for any poll that's still queued.
+                *future = None;
+                drop(future);
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Task>) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        // Wakers get called from all sorts of places, workers included, so
+        // this mustn't block behind a full queue. Once the pool has shut down
+        // the task is just dropped.
+        let shared = Arc::clone(&self.shared);
+        let _ = shared.submit(Priority::Normal, move || self.run(), PushMode::Force);
+    }
+}
+
+pub(crate) fn spawn<F>(pool: &ThreadPool, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let task = Task::new(pool, future);
+    pool.execute(move || task.run());
+}
+
+/// Runs futures on the pool, the same as [`ThreadPool::spawn_future`], for
+/// code that's written against the `futures` executor traits.
+///
+/// Where `spawn_future` would panic, `spawn_obj` gives back
+/// `SpawnError::shutdown()` instead. That covers a pool that has no live
+/// workers left, as well as one that's been shut down, since either way
+/// the future would never run.
+impl Spawn for ThreadPool {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        let task = Task::new(self, future);
+        self.try_execute(move || task.run())
+            .map_err(|_| SpawnError::shutdown())
+    }
+
+    fn status(&self) -> Result<(), SpawnError> {
+        if self.is_accepting() {
+            Ok(())
+        } else {
+            Err(SpawnError::shutdown())
+        }
+    }
+}
+
+// Wakes up the thread sitting in `block_on`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<ThreadWaker>) {
+        self.0.unpark();
+    }
+}
+
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    // `park` can return without anyone waking us, but polling again is
+    // harmless, so there's no need to tell the two apart.
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{self, Future},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        task::{Context, Poll, Waker},
+        thread,
+        time::Duration,
+    };
+
+    use crate::ThreadPool;
+
+    // A value handed to a future from outside, waking it when it arrives.
+    #[derive(Default)]
+    struct Slot<T> {
+        value: Mutex<(Option<T>, Option<Waker>)>,
+    }
+
+    impl<T> Slot<T> {
+        fn fill(&self, value: T) {
+            let mut slot = self.value.lock().unwrap();
+            slot.0 = Some(value);
+
+            if let Some(waker) = slot.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    struct Wait<T>(Arc<Slot<T>>);
+
+    impl<T> Future for Wait<T> {
+        type Output = T;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            let mut slot = self.0.value.lock().unwrap();
+
+            match slot.0.take() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    slot.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    // Pending once, waking itself straight away.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // Sets its flag when it's dropped.
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn future_that_yields_runs_to_completion() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn_future(async move {
+            let a = future::ready(1).await;
+            YieldNow(false).await;
+            let b = future::ready(2).await;
+            tx.send(a + b).unwrap();
+        });
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(3));
+    }
+
+    #[test]
+    fn future_is_polled_again_once_woken() {
+        let pool = ThreadPool::new(2);
+        let slot = Arc::new(Slot::default());
+        let (tx, rx) = mpsc::channel();
+
+        let wait = Wait(Arc::clone(&slot));
+        pool.spawn_future(async move {
+            tx.send(wait.await).unwrap();
+        });
+
+        // Nothing to do until it's been woken.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        slot.fill(7);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(7));
+    }
+
+    #[test]
+    fn future_woken_after_shutdown_is_dropped_unpolled() {
+        let pool = ThreadPool::new(1);
+        let slot = Arc::new(Slot::<()>::default());
+        let dropped = Arc::new(AtomicBool::new(false));
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        let wait = Wait(Arc::clone(&slot));
+        let guard = Dropped(Arc::clone(&dropped));
+        let counted = Arc::clone(&polls);
+        pool.spawn_future(async move {
+            let _guard = guard;
+            counted.fetch_add(1, Ordering::SeqCst);
+            wait.await;
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Wait for the first poll to park the waker in the slot.
+        while slot.value.lock().unwrap().1.is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        pool.shutdown();
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        slot.fill(());
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panicking_future_is_reported_like_a_job() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        pool.set_panic_handler(move |payload| {
+            let message = payload.downcast_ref::<&str>().copied();
+            tx.send(message.map(str::to_owned)).unwrap();
+        });
+
+        pool.spawn_future(async {
+            YieldNow(false).await;
+            panic!("boom");
+        });
+
+        let message = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(message.as_deref(), Some("boom"));
+
+        pool.join().unwrap();
+        assert_eq!(pool.panicked_count(), 1);
+        assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+    }
+
+    #[test]
+    fn spawn_obj_runs_the_future_until_the_pool_shuts_down() {
+        use futures_task::{FutureObj, Spawn};
+
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn_obj(FutureObj::new(Box::new(async move {
+            let a = future::ready(1).await;
+            YieldNow(false).await;
+            tx.send(a + 2).unwrap();
+        })))
+        .unwrap();
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(3));
+        assert!(pool.status().is_ok());
+
+        let other = pool.clone();
+        pool.shutdown();
+        assert!(other.status().unwrap_err().is_shutdown());
+        let err = other
+            .spawn_obj(FutureObj::new(Box::new(async {})))
+            .unwrap_err();
+        assert!(err.is_shutdown());
+    }
+
+    #[test]
+    fn block_on_returns_a_ready_value() {
+        let pool = ThreadPool::new(1);
+        assert_eq!(pool.block_on(future::ready(42)), 42);
+    }
+
+    #[test]
+    fn block_on_wakes_when_the_pool_finishes_the_work() {
+        let pool = ThreadPool::new(2);
+        let slot = Arc::new(Slot::default());
+
+        let filler = Arc::clone(&slot);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(20));
+            filler.fill(40 + 2);
+        });
+
+        assert_eq!(pool.block_on(Wait(slot)), 42);
+    }
+}
@@ -0,0 +1,1888 @@
+// Behaviour tests for `ThreadPool` as a whole. Tests for the other types
+// live next to them, in their own modules.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Barrier, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    CancelToken, Distribution, ExecuteError, JobError, PoolCreationError, Priority, QueueOrder,
+    ReentrantWait, RejectionPolicy, ShutdownResult, ThreadPool, ThreadPoolBuilder,
+};
+
+// Run `f` on a thread of its own and give up on it after `timeout`, so a
+// test that deadlocks fails instead of hanging the whole run.
+fn within<T: Send + 'static>(timeout: Duration, f: impl FnOnce() -> T + Send + 'static) -> T {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    rx.recv_timeout(timeout).expect("panicked or timed out")
+}
+
+// Poll `done` until it holds, giving up after a few seconds.
+fn eventually(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A job that tells `started` once it's running, then blocks until the
+// sender that comes with it is dropped.
+fn blocker(started: &mpsc::Sender<()>) -> (mpsc::Sender<()>, impl FnOnce() + Send + 'static) {
+    let started = started.clone();
+    let (release, wait) = mpsc::channel::<()>();
+
+    let job = move || {
+        started.send(()).unwrap();
+        let _ = wait.recv();
+    };
+
+    (release, job)
+}
+
+#[test]
+fn timeout_cancels_the_token() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    let started = Instant::now();
+
+    pool.execute_with_timeout(Duration::from_millis(50), move |token| {
+        token.cancelled().wait();
+        tx.send(started.elapsed()).unwrap();
+    });
+
+    let waited = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(waited >= Duration::from_millis(50));
+}
+
+#[test]
+fn token_is_left_alone_before_the_timeout() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+
+    pool.execute_with_timeout(Duration::from_secs(60), move |token| {
+        tx.send(token.is_cancelled()).unwrap();
+    });
+
+    assert!(!rx.recv_timeout(Duration::from_secs(2)).unwrap());
+}
+
+#[test]
+fn dropping_the_pool_cancels_pending_timeouts() {
+    let (started_tx, started_rx) = mpsc::channel();
+    let pool = ThreadPool::new(1);
+
+    pool.execute_with_timeout(Duration::from_secs(60), move |token| {
+        started_tx.send(()).unwrap();
+        token.cancelled().wait();
+    });
+
+    started_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    within(Duration::from_secs(2), move || drop(pool));
+}
+
+#[test]
+fn timeout_job_started_during_shutdown_is_already_cancelled() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    let gate = Arc::new(Barrier::new(2));
+
+    // Hold the only worker until shutdown has closed the timer.
+    let held = Arc::clone(&gate);
+    pool.execute(move || {
+        held.wait();
+        thread::sleep(Duration::from_millis(50));
+    });
+    pool.execute_with_timeout(Duration::from_secs(60), move |token| {
+        tx.send(token.is_cancelled()).unwrap();
+    });
+
+    gate.wait();
+    within(Duration::from_secs(2), move || drop(pool));
+    assert!(rx.recv().unwrap());
+}
+
+#[test]
+fn broadcast_runs_once_on_every_worker() {
+    let pool = ThreadPool::new(3);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let log = Arc::clone(&seen);
+    pool.broadcast(move |id| log.lock().unwrap().push(id))
+        .unwrap();
+
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(seen, [0, 1, 2]);
+}
+
+#[test]
+fn broadcast_from_inside_a_job_is_refused() {
+    let pool = ThreadPool::new(2);
+
+    let result = within(Duration::from_secs(2), move || {
+        pool.spawn(|| {
+            let pool = ThreadPool::current().unwrap();
+            (pool.broadcast(|_| {}), pool.warmup())
+        })
+        .join()
+        .unwrap()
+    });
+
+    assert_eq!(result, (Err(ReentrantWait), Err(ReentrantWait)));
+}
+
+#[test]
+fn warmup_waits_for_every_worker() {
+    let pool = ThreadPool::new(4);
+    assert_eq!(pool.warmup(), Ok(()));
+}
+
+#[test]
+fn panicking_panic_handler_does_not_kill_the_worker() {
+    // All of it, drop included, since a dead worker leaves the pool's
+    // counts wrong and the waits in here hanging.
+    within(Duration::from_secs(2), || {
+        let pool = ThreadPool::new(1);
+        pool.set_panic_handler(|_| panic!("the handler panicked too"));
+
+        pool.execute(|| panic!("boom"));
+        pool.join().unwrap();
+
+        // The pool's only worker is still there to take jobs.
+        assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+        assert_eq!(pool.panicked_count(), 1);
+    });
+}
+
+#[test]
+fn autoscaled_pool_grows_under_load_then_settles() {
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .autoscale(1, 4)
+        .scale_cooldown(Duration::ZERO)
+        .build()
+        .unwrap();
+
+    for _ in 0..40 {
+        pool.execute(|| thread::sleep(Duration::from_millis(50)));
+    }
+
+    eventually(|| pool.len() > 1);
+    assert!(pool.len() <= 4);
+
+    pool.join().unwrap();
+    eventually(|| pool.len() == 1);
+}
+
+#[test]
+fn autoscaler_does_not_wait_for_a_busy_worker_it_lets_go() {
+    within(Duration::from_secs(10), || {
+        let pool = ThreadPoolBuilder::new()
+            .size(1)
+            .autoscale(1, 2)
+            .scale_cooldown(Duration::ZERO)
+            .build()
+            .unwrap();
+
+        let (started_tx, started_rx) = mpsc::channel();
+
+        // Two jobs at once makes the pool grow, and the second runs on the
+        // new worker.
+        let (release_first, first) = blocker(&started_tx);
+        let (release_second, second) = blocker(&started_tx);
+        pool.execute(first);
+        started_rx.recv().unwrap();
+        pool.execute(second);
+        started_rx.recv().unwrap();
+
+        // Freeing up the first worker leaves one idle, so the pool shrinks
+        // again, letting go of the newer worker while it's still busy.
+        drop(release_first);
+        thread::sleep(Duration::from_millis(500));
+
+        // The monitor is still free to grow the pool for a new backlog.
+        let (release_third, third) = blocker(&started_tx);
+        pool.execute(third);
+        started_rx.recv().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        drop((release_second, release_third));
+    });
+}
+
+#[test]
+fn autoscaler_keeps_round_robin_jobs_when_it_lets_a_worker_go() {
+    within(Duration::from_secs(10), || {
+        let pool = ThreadPoolBuilder::new()
+            .size(1)
+            .autoscale(1, 3)
+            .scale_cooldown(Duration::ZERO)
+            .distribution(Distribution::RoundRobin)
+            .build()
+            .unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        // Bursts of work with quiet spells in between, so the pool keeps
+        // growing and letting workers go while jobs are being handed out.
+        for _ in 0..5 {
+            for _ in 0..30 {
+                let count = Arc::clone(&count);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(2));
+                    count.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+
+            thread::sleep(Duration::from_millis(150));
+        }
+
+        pool.join().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 150);
+    });
+}
+
+#[test]
+fn building_with_bad_autoscale_bounds_fails() {
+    for (min, max) in [(0, 4), (3, 2)] {
+        let err = ThreadPoolBuilder::new()
+            .autoscale(min, max)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PoolCreationError::InvalidAutoscale { min: m, max: n } if (m, n) == (min, max)
+        ));
+    }
+}
+
+#[test]
+fn jobs_sent_to_a_worker_all_run_on_its_thread() {
+    let pool = ThreadPool::new(4);
+    let (tx, rx) = mpsc::channel();
+
+    for _ in 0..20 {
+        let tx = tx.clone();
+        pool.execute_on(0, move || tx.send(thread::current().id()).unwrap())
+            .unwrap();
+    }
+
+    drop(tx);
+    let threads: Vec<_> = rx.iter().collect();
+    assert_eq!(threads.len(), 20);
+    assert!(threads.iter().all(|id| *id == threads[0]));
+}
+
+#[test]
+fn jobs_sent_to_a_worker_wait_while_paused() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    pool.pause();
+
+    pool.execute_on(0, move || tx.send(()).unwrap()).unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    pool.resume();
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+}
+
+#[test]
+fn sending_to_an_unknown_worker_fails() {
+    let pool = ThreadPool::new(2);
+    let err = pool.execute_on(99, || ()).unwrap_err();
+    assert!(matches!(err, ExecuteError::UnknownWorker(_)));
+}
+
+#[test]
+fn building_a_pool_of_zero_threads_fails() {
+    assert!(matches!(
+        ThreadPool::build(0),
+        Err(PoolCreationError::ZeroSize)
+    ));
+    assert_eq!(ThreadPool::build(4).unwrap().len(), 4);
+}
+
+#[test]
+fn executing_on_a_shut_down_pool_gives_the_job_back() {
+    let pool = ThreadPool::new(2);
+    let handle = pool.clone();
+    pool.shutdown();
+
+    let (tx, rx) = mpsc::channel();
+    let err = handle.try_execute(move || tx.send(7).unwrap()).unwrap_err();
+    assert!(matches!(err, ExecuteError::Shutdown(_)));
+
+    err.into_inner()();
+    assert_eq!(rx.try_recv(), Ok(7));
+}
+
+#[test]
+fn executing_with_no_workers_left_fails() {
+    let pool = ThreadPool::new(2);
+    pool.remove_workers(2);
+
+    let err = pool.try_execute(|| ()).unwrap_err();
+    assert!(matches!(err, ExecuteError::NoWorkers(_)));
+}
+
+#[test]
+fn creation_error_reads_well_and_boxes() {
+    let err = ThreadPool::build(0).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "thread pool size must be greater than zero"
+    );
+
+    let boxed: Box<dyn Error> = Box::new(err);
+    assert!(boxed.source().is_none());
+}
+
+#[test]
+fn builder_makes_a_working_pool() {
+    let pool = ThreadPoolBuilder::new()
+        .size(3)
+        .thread_name_prefix("custom")
+        .build()
+        .unwrap();
+
+    assert_eq!(pool.len(), 3);
+    assert_eq!(pool.spawn(|| 40 + 2).join(), Ok(42));
+}
+
+#[test]
+fn workers_are_named_after_the_prefix() {
+    let name = || thread::current().name().map(str::to_owned);
+
+    let pool = ThreadPool::new(1);
+    assert_eq!(
+        pool.spawn(name).join(),
+        Ok(Some("rustchat-worker-0".to_owned()))
+    );
+
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .thread_name_prefix("custom")
+        .build()
+        .unwrap();
+    assert_eq!(pool.spawn(name).join(), Ok(Some("custom-0".to_owned())));
+}
+
+#[test]
+fn big_stack_fits_deep_recursion() {
+    // Around 8 MiB deep, well past the usual 2 MiB default. Running it on a
+    // small stack would overflow, which aborts the whole test run, so only
+    // the side that should work is checked.
+    fn recurse(depth: usize) -> usize {
+        let frame = std::hint::black_box([0u8; 1024]);
+
+        match depth {
+            0 => frame[0] as usize,
+            _ => recurse(depth - 1) + frame[depth % 1024] as usize,
+        }
+    }
+
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .stack_size(64 * 1024 * 1024)
+        .build()
+        .unwrap();
+
+    assert_eq!(pool.spawn(|| recurse(8 * 1024)).join(), Ok(0));
+}
+
+#[test]
+fn worker_survives_a_panicking_job() {
+    let pool = ThreadPool::new(1);
+
+    pool.execute(|| panic!("boom"));
+    pool.execute(|| std::panic::panic_any(17));
+    assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.panicked_count(), 2);
+}
+
+#[test]
+fn dropping_the_pool_while_unwinding_does_not_abort() {
+    let unwound = thread::spawn(|| {
+        let pool = ThreadPool::new(2);
+        pool.execute(|| panic!("a job panicked"));
+        pool.join().unwrap();
+
+        // The pool is dropped on the way out of this panic. Panicking again
+        // in there would abort the whole test run.
+        panic!("the caller panicked");
+    })
+    .join();
+
+    assert!(unwound.is_err());
+}
+
+#[test]
+fn active_count_follows_running_jobs() {
+    let pool = ThreadPool::new(2);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+
+    pool.execute(job);
+    started_rx.recv().unwrap();
+    assert_eq!(pool.active_count(), 1);
+
+    drop(release);
+    pool.join().unwrap();
+    assert_eq!(pool.active_count(), 0);
+
+    // A job that panics still stops counting as active.
+    pool.execute(|| panic!("boom"));
+    pool.join().unwrap();
+    assert_eq!(pool.active_count(), 0);
+}
+
+#[test]
+fn queued_count_counts_jobs_waiting_behind_a_busy_worker() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    for queued in 1..=3 {
+        pool.execute(|| ());
+        assert_eq!(pool.queued_count(), queued);
+    }
+
+    // The running job isn't counted as queued.
+    assert_eq!(pool.active_count(), 1);
+
+    drop(release);
+    pool.join().unwrap();
+    assert_eq!(pool.queued_count(), 0);
+}
+
+#[test]
+fn shutdown_finishes_every_queued_job() {
+    let pool = ThreadPool::new(2);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..20 {
+        let count = Arc::clone(&count);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(1));
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pool.shutdown();
+    assert_eq!(count.load(Ordering::Relaxed), 20);
+}
+
+#[test]
+fn shutdown_timeout_reports_workers_it_gave_up_on() {
+    let pool = ThreadPool::new(2);
+    let (started_tx, started_rx) = mpsc::channel();
+
+    pool.execute(|| ());
+    pool.execute(move || {
+        started_tx.send(()).unwrap();
+        thread::sleep(Duration::from_secs(1));
+    });
+    started_rx.recv().unwrap();
+
+    let started = Instant::now();
+    let result = pool.shutdown_timeout(Duration::from_millis(100));
+
+    assert_eq!(result, ShutdownResult::TimedOut { unfinished: 1 });
+    assert!(started.elapsed() < Duration::from_millis(900));
+}
+
+#[test]
+fn shutdown_timeout_completes_when_jobs_are_quick() {
+    let pool = ThreadPool::new(2);
+    pool.execute(|| thread::sleep(Duration::from_millis(10)));
+
+    let result = pool.shutdown_timeout(Duration::from_secs(2));
+    assert_eq!(result, ShutdownResult::Complete);
+}
+
+#[test]
+fn join_waits_for_every_job_and_leaves_the_pool_usable() {
+    let pool = ThreadPool::new(4);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    for round in 1..=2 {
+        for _ in 0..50 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        pool.join().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), round * 50);
+    }
+}
+
+#[test]
+fn panic_handler_sees_each_panic_once() {
+    let pool = ThreadPool::new(2);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let handler_seen = Arc::clone(&seen);
+    pool.set_panic_handler(move |payload| {
+        let message = payload.downcast_ref::<&str>().copied().unwrap_or("?");
+        handler_seen.lock().unwrap().push(message.to_owned());
+    });
+
+    pool.execute(|| ());
+    pool.execute(|| panic!("boom"));
+    pool.join().unwrap();
+
+    assert_eq!(*seen.lock().unwrap(), ["boom"]);
+}
+
+#[test]
+fn each_job_is_timed_while_it_runs() {
+    let pool = ThreadPool::new(1);
+    assert_eq!(pool.duration_percentiles().0, Duration::ZERO);
+
+    pool.execute(|| thread::sleep(Duration::from_millis(30)));
+    pool.join().unwrap();
+
+    let (p50, _, p99) = pool.duration_percentiles();
+    assert!(p50 >= Duration::from_millis(30));
+    assert_eq!(p50, p99);
+}
+
+#[test]
+fn completed_count_includes_jobs_that_panicked() {
+    let pool = ThreadPool::new(4);
+    pool.set_panic_handler(|_| {});
+
+    for i in 0..100 {
+        pool.execute(move || assert!(i % 10 != 0));
+    }
+
+    pool.join().unwrap();
+    assert_eq!(pool.completed_count(), 100);
+    assert_eq!(pool.panicked_count(), 10);
+}
+
+#[test]
+fn default_pool_has_at_least_one_worker() {
+    let pool = ThreadPool::default();
+    assert!(!pool.is_empty());
+    assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+}
+
+#[test]
+fn workers_outlive_all_but_the_last_handle() {
+    let pool = ThreadPool::new(2);
+    let other = pool.clone();
+    drop(pool);
+
+    assert!(!other.is_shutdown());
+    assert_eq!(other.spawn(|| 1).join(), Ok(1));
+
+    let weak = other.downgrade();
+    drop(other);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn debug_shows_the_worker_count() {
+    let pool = ThreadPool::new(3);
+    assert_eq!(
+        format!("{pool:?}"),
+        "ThreadPool { workers: 3, accepting: true }"
+    );
+}
+
+#[test]
+fn higher_priority_jobs_run_first() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    let jobs = [
+        (Priority::Low, 1),
+        (Priority::High, 2),
+        (Priority::Normal, 3),
+        (Priority::Low, 4),
+        (Priority::High, 5),
+    ];
+
+    for (priority, n) in jobs {
+        let order = Arc::clone(&order);
+        pool.execute_with_priority(priority, move || order.lock().unwrap().push(n));
+    }
+
+    drop(release);
+    pool.join().unwrap();
+    assert_eq!(*order.lock().unwrap(), [2, 5, 3, 1, 4]);
+}
+
+#[test]
+fn delayed_job_waits_for_its_delay() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    let started = Instant::now();
+
+    pool.execute_after(Duration::from_millis(100), move || tx.send(()).unwrap());
+
+    assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn delayed_job_is_dropped_at_shutdown() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel::<()>();
+
+    pool.execute_after(Duration::from_secs(60), move || tx.send(()).unwrap());
+    within(Duration::from_secs(2), move || drop(pool));
+
+    // Dropped, so the sender went with it, without running.
+    assert_eq!(rx.recv(), Err(mpsc::RecvError));
+}
+
+#[test]
+fn periodic_job_runs_every_interval_until_cancelled() {
+    let pool = ThreadPool::new(1);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let runs = Arc::clone(&count);
+    let handle = pool.execute_every(Duration::from_millis(50), move || {
+        runs.fetch_add(1, Ordering::Relaxed);
+    });
+
+    thread::sleep(Duration::from_millis(275));
+    let before = count.load(Ordering::Relaxed);
+    assert!((3..=6).contains(&before), "ran {before} times");
+
+    handle.cancel();
+    assert!(handle.is_cancelled());
+
+    // A run that had already started when we cancelled may still finish.
+    thread::sleep(Duration::from_millis(20));
+    let cancelled_at = count.load(Ordering::Relaxed);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(count.load(Ordering::Relaxed), cancelled_at);
+}
+
+#[test]
+fn polling_job_stops_soon_after_its_timeout() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    let started = Instant::now();
+
+    pool.execute_with_timeout(Duration::from_millis(50), move |token| {
+        while !token.is_cancelled() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        tx.send(started.elapsed()).unwrap();
+    });
+
+    let ran_for = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(ran_for >= Duration::from_millis(50));
+    assert!(ran_for < Duration::from_millis(500));
+}
+
+#[test]
+fn every_job_runs_exactly_once_under_load() {
+    let pool = ThreadPool::new(8);
+    let runs: Arc<Vec<AtomicUsize>> = Arc::new((0..10_000).map(|_| AtomicUsize::new(0)).collect());
+
+    for i in 0..runs.len() {
+        let runs = Arc::clone(&runs);
+        pool.execute(move || {
+            runs[i].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pool.join().unwrap();
+    assert!(runs.iter().all(|n| n.load(Ordering::Relaxed) == 1));
+    assert_eq!(pool.completed_count(), runs.len());
+}
+
+#[test]
+fn jobs_from_many_threads_all_run_and_workers_exit_with_the_last_handle() {
+    // Counts worker threads as they exit, through a thread-local each one
+    // sets up when it starts.
+    struct OnExit(Arc<AtomicUsize>);
+
+    impl Drop for OnExit {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    thread_local! {
+        static ON_EXIT: RefCell<Option<OnExit>> = const { RefCell::new(None) };
+    }
+
+    let exited = Arc::new(AtomicUsize::new(0));
+    let worker_exited = Arc::clone(&exited);
+    let pool = ThreadPoolBuilder::new()
+        .size(4)
+        .worker_init(move |_| {
+            ON_EXIT.set(Some(OnExit(Arc::clone(&worker_exited))));
+        })
+        .build()
+        .unwrap();
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let submitters: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            let count = Arc::clone(&count);
+
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let count = Arc::clone(&count);
+                    pool.execute(move || {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        })
+        .collect();
+
+    for submitter in submitters {
+        submitter.join().unwrap();
+    }
+
+    within(Duration::from_secs(5), move || drop(pool));
+    assert_eq!(count.load(Ordering::Relaxed), 4000);
+    assert_eq!(exited.load(Ordering::SeqCst), 4);
+}
+
+// A one-worker pool with room for `capacity` waiting jobs, its worker tied
+// up until the sender that comes with it is dropped.
+fn busy_bounded_pool(capacity: usize, policy: RejectionPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .queue_capacity(capacity)
+        .rejection_policy(policy)
+        .build()
+        .unwrap();
+
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+
+    // Sent straight to the worker, so it doesn't need room in the queue,
+    // whatever the worker's up to.
+    pool.execute_on(0, job).unwrap();
+    started_rx.recv().unwrap();
+
+    (pool, release)
+}
+
+#[test]
+fn full_queue_gives_the_job_straight_back() {
+    let (pool, release) = busy_bounded_pool(1, RejectionPolicy::Block);
+
+    pool.try_execute_now(|| ()).unwrap();
+    let err = pool.try_execute_now(|| ()).unwrap_err();
+    assert!(matches!(err, ExecuteError::Full(_)));
+
+    drop(release);
+}
+
+#[test]
+fn full_queue_blocks_execute_until_there_is_room() {
+    let (pool, release) = busy_bounded_pool(1, RejectionPolicy::Block);
+    pool.execute(|| ());
+
+    let (tx, rx) = mpsc::channel();
+    let submitter = pool.clone();
+    thread::spawn(move || {
+        submitter.execute(|| ());
+        tx.send(()).unwrap();
+    });
+
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(release);
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+}
+
+#[test]
+fn zero_capacity_only_hands_jobs_to_a_waiting_worker() {
+    let (pool, release) = busy_bounded_pool(0, RejectionPolicy::Block);
+
+    let err = pool.try_execute_now(|| ()).unwrap_err();
+    assert!(matches!(err, ExecuteError::Full(_)));
+
+    // Once the worker is free and waiting, it takes the job straight away.
+    drop(release);
+    let (tx, rx) = mpsc::channel();
+    eventually(|| {
+        let tx = tx.clone();
+        pool.try_execute_now(move || tx.send(()).unwrap()).is_ok()
+    });
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+}
+
+#[test]
+fn caller_runs_policy_runs_the_job_on_the_submitting_thread() {
+    let (pool, release) = busy_bounded_pool(0, RejectionPolicy::CallerRuns);
+    let (tx, rx) = mpsc::channel();
+
+    pool.execute(move || tx.send(thread::current().id()).unwrap());
+    assert_eq!(rx.try_recv(), Ok(thread::current().id()));
+
+    drop(release);
+}
+
+#[test]
+fn discard_policy_drops_the_job() {
+    let (pool, release) = busy_bounded_pool(0, RejectionPolicy::Discard);
+    let (tx, rx) = mpsc::channel::<()>();
+
+    pool.execute(move || tx.send(()).unwrap());
+    assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+
+    drop(release);
+}
+
+#[test]
+fn added_workers_take_jobs_alongside_the_rest() {
+    within(Duration::from_secs(5), || {
+        let pool = ThreadPool::new(2);
+        pool.add_workers(2).unwrap();
+        assert_eq!(pool.len(), 4);
+
+        // Only passes once all four jobs are running at the same time.
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.spawn(move || {
+                    barrier.wait();
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn removing_workers_keeps_the_queued_jobs() {
+    within(Duration::from_secs(5), || {
+        let pool = ThreadPool::new(4);
+        let count = Arc::new(AtomicUsize::new(0));
+        pool.pause();
+
+        for _ in 0..20 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        assert_eq!(pool.remove_workers(2), 2);
+        assert_eq!(pool.len(), 2);
+
+        pool.resume();
+        pool.join().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 20);
+
+        // Asking for more than there are just takes all of them.
+        assert_eq!(pool.remove_workers(5), 2);
+        assert!(pool.is_empty());
+    });
+}
+
+#[test]
+fn removing_a_worker_while_paused_keeps_the_jobs_sent_to_it() {
+    within(Duration::from_secs(5), || {
+        let pool = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        pool.pause();
+
+        for _ in 0..10 {
+            let count = Arc::clone(&count);
+            pool.execute_on(1, move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        assert_eq!(pool.remove_workers(1), 1);
+
+        pool.resume();
+        pool.join().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 10);
+    });
+}
+
+#[test]
+fn retiring_one_worker_stops_exactly_one() {
+    let pool = ThreadPool::new(3);
+    pool.warmup().unwrap();
+    assert_eq!(pool.remove_workers(1), 1);
+
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    let seen = Arc::clone(&threads);
+    pool.broadcast(move |_| {
+        seen.lock().unwrap().insert(thread::current().id());
+    })
+    .unwrap();
+
+    assert_eq!(threads.lock().unwrap().len(), 2);
+    assert_eq!(pool.len(), 2);
+}
+
+#[test]
+fn worker_init_runs_on_the_worker_before_its_jobs() {
+    thread_local! {
+        static WORKER_ID: RefCell<Option<usize>> = const { RefCell::new(None) };
+    }
+
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .worker_init(|id| WORKER_ID.set(Some(id)))
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        pool.spawn(|| WORKER_ID.with_borrow(|id| *id)).join(),
+        Ok(Some(0))
+    );
+}
+
+#[cfg(feature = "affinity")]
+#[test]
+fn pinned_workers_still_run_jobs() {
+    let pool = ThreadPoolBuilder::new()
+        .size(2)
+        .pin_to_cores(true)
+        .build()
+        .unwrap();
+
+    let results: Vec<_> = (0..4).map(|i| pool.spawn(move || i * 2)).collect();
+    let results: Vec<_> = results.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, [0, 2, 4, 6]);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn every_job_runs_in_its_own_span() {
+    use std::{fmt, sync::atomic::AtomicU64};
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    // Picks the numbers out of a span's fields.
+    #[derive(Default)]
+    struct Fields(HashMap<&'static str, u128>);
+
+    impl Visit for Fields {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name(), value.into());
+        }
+
+        fn record_u128(&mut self, field: &Field, value: u128) {
+            self.0.insert(field.name(), value);
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+    }
+
+    // Keeps the fields of every `worker.job` span, by span id.
+    #[derive(Default)]
+    struct Spans {
+        next_id: AtomicU64,
+        jobs: Arc<Mutex<HashMap<u64, Fields>>>,
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if span.metadata().name() == "worker.job" {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                self.jobs.lock().unwrap().insert(id, fields);
+            }
+
+            span::Id::from_u64(id)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+            if let Some(fields) = self.jobs.lock().unwrap().get_mut(&span.into_u64()) {
+                values.record(fields);
+            }
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    // The workers are threads of their own, so the subscriber has to be the
+    // global one, which every other test's pool reports to as well.
+    let spans = Spans::default();
+    let jobs = Arc::clone(&spans.jobs);
+    tracing::subscriber::set_global_default(spans).unwrap();
+
+    let pool = ThreadPool::new(2);
+    for _ in 0..10 {
+        pool.execute(|| thread::sleep(Duration::from_millis(5)));
+    }
+    pool.join().unwrap();
+
+    let jobs = jobs.lock().unwrap();
+    let ours: Vec<_> = jobs
+        .values()
+        .filter(|fields| fields.0.get("pool.id") == Some(&pool.id().into()))
+        .collect();
+
+    assert_eq!(ours.len(), 10);
+    assert!(ours.iter().all(|fields| fields.0["worker.id"] < 2
+        && fields.0.get("duration_ms").is_some_and(|&ms| ms >= 5)));
+}
+
+#[test]
+fn execute_all_hands_over_every_job() {
+    let pool = ThreadPool::new(4);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let jobs: Vec<_> = (0..50)
+        .map(|_| {
+            let count = Arc::clone(&count);
+            move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .collect();
+
+    assert_eq!(pool.execute_all(jobs), 50);
+    pool.join().unwrap();
+    assert_eq!(count.load(Ordering::Relaxed), 50);
+}
+
+#[test]
+fn execute_all_stops_at_a_shut_down_pool() {
+    let pool = ThreadPool::new(1);
+    let other = pool.clone();
+    pool.shutdown();
+
+    assert_eq!(other.execute_all((0..5).map(|_| || ())), 0);
+}
+
+#[test]
+fn join_all_keeps_going_past_a_panic() {
+    let pool = ThreadPool::new(2);
+    pool.set_panic_handler(|_| {});
+
+    let jobs: Vec<Box<dyn FnOnce() -> i32 + Send>> =
+        vec![Box::new(|| 1), Box::new(|| panic!("boom")), Box::new(|| 3)];
+
+    let results = pool.join_all(jobs);
+    assert_eq!(
+        results,
+        [Ok(1), Err(JobError::Panic("boom".to_owned())), Ok(3)]
+    );
+}
+
+#[test]
+#[should_panic(expected = "par_collect job failed: Panic(\"boom\")")]
+fn par_collect_passes_on_a_panic() {
+    let pool = ThreadPool::new(2);
+    pool.set_panic_handler(|_| {});
+
+    let jobs: Vec<Box<dyn FnOnce() -> i32 + Send>> =
+        vec![Box::new(|| 1), Box::new(|| panic!("boom")), Box::new(|| 3)];
+
+    let _: Vec<i32> = pool.par_collect(jobs);
+}
+
+#[test]
+fn retried_job_succeeds_on_its_third_attempt() {
+    let pool = ThreadPool::new(1);
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    let tries = Arc::clone(&attempts);
+    pool.execute_with_retry(3, move || {
+        let attempt = tries.fetch_add(1, Ordering::SeqCst) + 1;
+        assert!(attempt >= 3, "attempt {attempt} failed");
+    });
+
+    pool.join().unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(pool.panicked_count(), 0);
+}
+
+#[test]
+fn retried_job_gives_up_after_its_last_attempt() {
+    let pool = ThreadPool::new(1);
+    let attempts = Arc::new(AtomicUsize::new(0));
+    pool.set_panic_handler(|_| {});
+
+    let tries = Arc::clone(&attempts);
+    pool.execute_with_retry(2, move || {
+        tries.fetch_add(1, Ordering::SeqCst);
+        panic!("always fails");
+    });
+
+    pool.join().unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.panicked_count(), 1);
+}
+
+#[test]
+fn paused_pool_holds_jobs_until_resumed() {
+    let pool = ThreadPool::new(2);
+    let count = Arc::new(AtomicUsize::new(0));
+    pool.pause();
+
+    for _ in 0..10 {
+        let count = Arc::clone(&count);
+        pool.execute(move || {
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+    assert_eq!(pool.queued_count(), 10);
+
+    pool.resume();
+    pool.join().unwrap();
+    assert_eq!(count.load(Ordering::Relaxed), 10);
+}
+
+#[test]
+fn clear_queue_discards_only_the_waiting_jobs() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+    let count = Arc::new(AtomicUsize::new(0));
+
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    for _ in 0..5 {
+        let count = Arc::clone(&count);
+        pool.execute(move || {
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    let handle = pool.spawn(|| ());
+
+    assert_eq!(pool.clear_queue(), 6);
+    assert_eq!(pool.queued_count(), 0);
+    assert_eq!(handle.join(), Err(JobError::Canceled));
+
+    drop(release);
+    pool.join().unwrap();
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+    assert_eq!(pool.completed_count(), 1);
+}
+
+#[test]
+fn supervisor_replaces_a_dead_worker() {
+    // Only the first worker to start dies.
+    let killed = Arc::new(AtomicBool::new(false));
+    let pool = ThreadPoolBuilder::new()
+        .size(2)
+        .supervise(Duration::from_millis(20))
+        .worker_init(move |_| {
+            if !killed.swap(true, Ordering::SeqCst) {
+                panic!("worker init failed");
+            }
+        })
+        .build()
+        .unwrap();
+
+    eventually(|| {
+        let stats = pool.worker_stats();
+        stats.len() == 2
+            && stats.iter().all(|worker| worker.is_alive)
+            && stats.iter().any(|worker| worker.id >= 2)
+    });
+
+    let ids: HashSet<_> = pool.worker_stats().iter().map(|worker| worker.id).collect();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+}
+
+#[test]
+fn stats_snapshot_adds_up() {
+    let pool = ThreadPool::new(3);
+    pool.set_panic_handler(|_| {});
+
+    for i in 0..30 {
+        pool.execute(move || assert!(i % 5 != 0));
+    }
+
+    pool.join().unwrap();
+    let stats = pool.stats();
+
+    assert_eq!(stats.workers, 3);
+    assert_eq!(stats.active, 0);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.completed, 30);
+    assert_eq!(stats.panicked, 6);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn stats_serialize_to_json() {
+    let pool = ThreadPool::new(2);
+    pool.spawn(|| ()).join().unwrap();
+    pool.join().unwrap();
+
+    let json = serde_json::to_value(pool.stats()).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "workers": 2,
+            "active": 0,
+            "queued": 0,
+            "completed": 1,
+            "panicked": 0,
+        })
+    );
+}
+
+#[test]
+fn wait_idle_returns_after_the_last_job() {
+    let pool = ThreadPool::new(2);
+    let finished = Arc::new(Mutex::new(Vec::new()));
+
+    for ms in [10, 30, 60] {
+        let finished = Arc::clone(&finished);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(ms));
+            finished.lock().unwrap().push(Instant::now());
+        });
+    }
+
+    pool.wait_idle().unwrap();
+    let returned = Instant::now();
+
+    let finished = finished.lock().unwrap();
+    assert_eq!(finished.len(), 3);
+    assert!(finished.iter().all(|at| *at <= returned));
+}
+
+#[test]
+fn idle_callback_fires_once_per_burst() {
+    let pool = ThreadPool::new(2);
+    let idled = Arc::new(AtomicUsize::new(0));
+
+    let count = Arc::clone(&idled);
+    pool.on_idle(move || {
+        count.fetch_add(1, Ordering::SeqCst);
+    });
+
+    // Paused, so the pool can't go idle partway through the burst.
+    pool.pause();
+    for _ in 0..20 {
+        pool.execute(|| thread::sleep(Duration::from_millis(1)));
+    }
+    pool.resume();
+
+    pool.join().unwrap();
+    eventually(|| idled.load(Ordering::SeqCst) == 1);
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(idled.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn broadcast_runs_on_each_workers_own_thread() {
+    let pool = ThreadPool::new(4);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let log = Arc::clone(&seen);
+    pool.broadcast(move |id| {
+        let name = thread::current().name().unwrap().to_owned();
+        log.lock().unwrap().push((id, name));
+    })
+    .unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 4);
+    assert!(seen
+        .iter()
+        .all(|(id, name)| *name == format!("rustchat-worker-{id}")));
+}
+
+#[test]
+fn worker_stats_add_up_to_the_completed_count() {
+    let pool = ThreadPool::new(3);
+
+    for _ in 0..60 {
+        pool.execute(|| thread::sleep(Duration::from_micros(100)));
+    }
+
+    pool.join().unwrap();
+    let stats = pool.worker_stats();
+
+    assert_eq!(stats.len(), 3);
+    assert!(stats.iter().all(|worker| worker.is_alive));
+    let total: usize = stats.iter().map(|worker| worker.jobs_completed).sum();
+    assert_eq!(total, pool.completed_count());
+}
+
+#[test]
+fn duration_percentiles_follow_the_jobs() {
+    let pool = ThreadPool::new(4);
+    pool.set_panic_handler(|_| {});
+
+    // 95 quick jobs and 5 slow ones, one of which panics.
+    for i in 0..100 {
+        pool.execute(move || {
+            if i % 20 == 0 {
+                thread::sleep(Duration::from_millis(50));
+                assert!(i != 0);
+            }
+        });
+    }
+
+    pool.join().unwrap();
+    let (p50, p95, p99) = pool.duration_percentiles();
+
+    assert!(p50 < Duration::from_millis(10), "p50 was {p50:?}");
+    assert!(p95 <= p99);
+    assert!(p99 >= Duration::from_millis(50), "p99 was {p99:?}");
+}
+
+#[test]
+fn jobs_behind_a_busy_worker_report_queue_wait() {
+    let pool = ThreadPool::new(1);
+    assert_eq!(pool.avg_queue_wait(), Duration::ZERO);
+
+    for _ in 0..5 {
+        pool.execute(|| thread::sleep(Duration::from_millis(20)));
+    }
+
+    pool.join().unwrap();
+
+    // The jobs waited 0, 20, 40, 60 and 80ms, give or take.
+    let wait = pool.avg_queue_wait();
+    assert!(wait >= Duration::from_millis(30), "waited {wait:?}");
+}
+
+#[test]
+fn peak_queue_depth_remembers_the_biggest_burst() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    for _ in 0..8 {
+        pool.execute(|| ());
+    }
+
+    drop(release);
+    pool.join().unwrap();
+    pool.execute(|| ());
+    pool.join().unwrap();
+
+    assert_eq!(pool.peak_queue_depth(), 8);
+}
+
+#[test]
+fn idle_workers_run_maintenance_every_interval() {
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let count = Arc::clone(&runs);
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .maintenance(Duration::from_millis(50), move |_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+        .build()
+        .unwrap();
+
+    thread::sleep(Duration::from_millis(300));
+    let ran = runs.load(Ordering::Relaxed);
+    assert!((3..=7).contains(&ran), "ran {ran} times");
+
+    drop(pool);
+}
+
+#[test]
+fn sender_reports_a_pool_with_no_workers_instead_of_panicking() {
+    let pool = ThreadPool::new(2);
+    let sender = pool.sender();
+    pool.remove_workers(2);
+
+    let err = sender.try_execute(|| ()).unwrap_err();
+    assert!(matches!(err, ExecuteError::NoWorkers(_)));
+    assert_eq!(err.to_string(), "thread pool has no live workers");
+}
+
+#[test]
+#[should_panic(expected = "NoWorkers")]
+fn execute_panics_on_a_pool_with_no_workers() {
+    let pool = ThreadPool::new(1);
+    pool.remove_workers(1);
+    pool.execute(|| ());
+}
+
+#[test]
+fn warmup_returns_once_every_worker_has_started() {
+    let started = Arc::new(AtomicUsize::new(0));
+
+    let count = Arc::clone(&started);
+    let pool = ThreadPoolBuilder::new()
+        .size(4)
+        .worker_init(move |_| {
+            thread::sleep(Duration::from_millis(20));
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+        .build()
+        .unwrap();
+
+    pool.warmup().unwrap();
+    assert_eq!(started.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn busy_pool_grows_up_to_its_max_size() {
+    within(Duration::from_secs(5), || {
+        let pool = ThreadPoolBuilder::new()
+            .size(1)
+            .max_size(4)
+            .build()
+            .unwrap();
+
+        // Four jobs that only finish once all of them are running.
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.spawn(move || {
+                    barrier.wait();
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(pool.len(), 4);
+
+        // It never goes past the maximum.
+        for _ in 0..20 {
+            pool.execute(|| thread::sleep(Duration::from_millis(1)));
+        }
+        assert_eq!(pool.len(), 4);
+    });
+}
+
+#[test]
+fn extra_workers_retire_after_the_keep_alive() {
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .max_size(3)
+        .keep_alive(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    let barrier = Arc::new(Barrier::new(3));
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            pool.spawn(move || {
+                barrier.wait();
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(pool.len(), 3);
+
+    eventually(|| pool.len() == 1);
+
+    // The pool's own worker stays, however long it's idle.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.spawn(|| 1).join(), Ok(1));
+}
+
+#[test]
+fn aborted_job_never_runs() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let ran = Arc::clone(&ran);
+        pool.execute_cancelable(move || ran.store(true, Ordering::SeqCst))
+    };
+    let kept = {
+        let ran = Arc::clone(&ran);
+        pool.spawn(move || ran.load(Ordering::SeqCst))
+    };
+
+    handle.abort();
+    assert!(handle.clone().is_aborted());
+    drop(release);
+
+    // The job queued behind it still runs, and sees nothing from the
+    // aborted one.
+    assert_eq!(kept.join(), Ok(false));
+    pool.join().unwrap();
+    assert!(!ran.load(Ordering::SeqCst));
+}
+
+#[test]
+fn round_robin_gives_every_worker_the_same_share() {
+    const PER_WORKER: usize = 5;
+
+    let pool = ThreadPoolBuilder::new()
+        .size(3)
+        .distribution(Distribution::RoundRobin)
+        .build()
+        .unwrap();
+
+    let counts = Arc::new(Mutex::new(HashMap::new()));
+
+    for _ in 0..PER_WORKER * 3 {
+        let counts = Arc::clone(&counts);
+        pool.execute(move || {
+            // Slow enough that a shared queue would let one worker fall
+            // behind the others.
+            thread::sleep(Duration::from_millis(1));
+            let name = thread::current().name().unwrap().to_owned();
+            *counts.lock().unwrap().entry(name).or_insert(0) += 1;
+        });
+    }
+    pool.join().unwrap();
+
+    let counts = counts.lock().unwrap();
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|&n| n == PER_WORKER), "{counts:?}");
+}
+
+#[test]
+fn round_robin_runs_every_job_while_the_pool_shrinks() {
+    within(Duration::from_secs(10), || {
+        let pool = ThreadPoolBuilder::new()
+            .size(4)
+            .distribution(Distribution::RoundRobin)
+            .build()
+            .unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..3 {
+                    thread::sleep(Duration::from_millis(5));
+                    pool.remove_workers(1);
+                }
+            });
+
+            for _ in 0..500 {
+                let count = Arc::clone(&count);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_micros(50));
+                    count.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+
+        pool.join().unwrap();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(count.load(Ordering::Relaxed), 500);
+    });
+}
+
+#[cfg(feature = "priority")]
+#[test]
+fn pools_run_jobs_whatever_their_thread_priority() {
+    // Raising the priority usually fails without extra privileges, which
+    // the pool should shrug off.
+    for priority in [crate::ThreadPriority::Low, crate::ThreadPriority::High] {
+        let pool = ThreadPoolBuilder::new()
+            .size(2)
+            .thread_priority(priority)
+            .build()
+            .unwrap();
+
+        assert_eq!(pool.spawn(|| 1 + 1).join(), Ok(2));
+    }
+}
+
+#[test]
+fn shutdown_now_hands_back_the_queued_jobs() {
+    let pool = ThreadPool::new(1);
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    let ran = Arc::new(AtomicUsize::new(0));
+    for _ in 0..3 {
+        let ran = Arc::clone(&ran);
+        pool.execute(move || {
+            ran.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    // It waits for the blocker, so let that go once the queue's been
+    // taken.
+    let watcher = pool.clone();
+    let releaser = thread::spawn(move || {
+        eventually(|| watcher.queued_count() == 0);
+        drop(release);
+    });
+
+    let jobs = within(Duration::from_secs(5), move || pool.shutdown_now());
+    releaser.join().unwrap();
+
+    assert_eq!(jobs.len(), 3);
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+    // They're still good to run elsewhere.
+    for job in jobs {
+        job();
+    }
+    assert_eq!(ran.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn lots_of_tiny_jobs_all_run() {
+    const JOBS: usize = 100_000;
+
+    let pool = ThreadPool::new(4);
+    let count = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+
+    for i in 0..JOBS {
+        let count = Arc::clone(&count);
+
+        // Every other one turns up already boxed, the way the timer and
+        // scopes hand them over.
+        if i % 2 == 0 {
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+        } else {
+            let job: crate::Job = Box::new(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+            pool.execute(job);
+        }
+    }
+    pool.join().unwrap();
+
+    assert_eq!(count.load(Ordering::Relaxed), JOBS);
+    eprintln!("{JOBS} tiny jobs took {:?}", started.elapsed());
+}
+
+#[test]
+fn lifo_runs_the_newest_job_first() {
+    let pool = ThreadPoolBuilder::new()
+        .size(1)
+        .queue_order(QueueOrder::Lifo)
+        .build()
+        .unwrap();
+
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release, job) = blocker(&started_tx);
+    pool.execute(job);
+    started_rx.recv().unwrap();
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for i in 0..5 {
+        let order = Arc::clone(&order);
+        pool.execute(move || order.lock().unwrap().push(i));
+    }
+
+    drop(release);
+    pool.join().unwrap();
+    assert_eq!(*order.lock().unwrap(), [4, 3, 2, 1, 0]);
+}
+
+#[test]
+fn shutdown_signal_drains_the_pool_and_turns_new_work_away() {
+    let token = CancelToken::new();
+    let pool = ThreadPool::new(1).with_shutdown_signal(token.clone());
+    let ran = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..5 {
+        let ran = Arc::clone(&ran);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(20));
+            ran.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    token.cancel();
+    eventually(|| pool.is_shutdown());
+
+    assert!(matches!(
+        pool.try_execute(|| ()),
+        Err(ExecuteError::Shutdown(_))
+    ));
+
+    // Everything that was queued before the signal still runs.
+    eventually(|| ran.load(Ordering::SeqCst) == 5);
+}
+
+#[test]
+fn barrier_holds_every_worker_until_the_last_one_arrives() {
+    let pool = ThreadPool::new(3);
+    pool.warmup().unwrap();
+
+    // Worker 0 is slow to get to the barrier.
+    let finished = Arc::new(Mutex::new(None));
+    {
+        let finished = Arc::clone(&finished);
+        pool.execute_on(0, move || {
+            thread::sleep(Duration::from_millis(200));
+            *finished.lock().unwrap() = Some(Instant::now());
+        })
+        .unwrap();
+    }
+
+    assert_eq!(pool.barrier(), 3);
+
+    // The other two get there straight away, but none of them runs what
+    // comes after the barrier until worker 0 has caught up.
+    let (tx, rx) = mpsc::channel();
+    for id in 0..3 {
+        let tx = tx.clone();
+        pool.execute_on(id, move || tx.send(Instant::now()).unwrap())
+            .unwrap();
+    }
+    drop(tx);
+
+    let after: Vec<Instant> = rx.iter().collect();
+    let finished = finished.lock().unwrap().unwrap();
+    assert_eq!(after.len(), 3);
+    assert!(after.iter().all(|&at| at >= finished));
+}
+
+#[test]
+fn size_falls_back_when_the_core_count_is_unknown() {
+    let unknown = || Err(std::io::Error::other("no idea"));
+    let four = || Ok(std::num::NonZeroUsize::new(4).unwrap());
+
+    assert_eq!(ThreadPoolBuilder::new().initial_size(unknown()), 1);
+    assert_eq!(
+        ThreadPoolBuilder::new()
+            .default_size(3)
+            .initial_size(unknown()),
+        3
+    );
+
+    // The fallback is only for when the count can't be had, and an
+    // explicit size beats both.
+    assert_eq!(
+        ThreadPoolBuilder::new()
+            .default_size(3)
+            .initial_size(four()),
+        4
+    );
+    assert_eq!(
+        ThreadPoolBuilder::new()
+            .size(2)
+            .default_size(3)
+            .initial_size(unknown()),
+        2
+    );
+
+    let pool = ThreadPoolBuilder::new()
+        .size(2)
+        .default_size(3)
+        .build()
+        .unwrap();
+    assert_eq!(pool.len(), 2);
+}
+
+#[test]
+fn a_short_job_behind_a_yielding_one_finishes_promptly() {
+    within(Duration::from_secs(5), || {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.pause();
+
+        let long = tx.clone();
+        pool.execute(move || {
+            for _ in 0..50 {
+                thread::sleep(Duration::from_millis(10));
+                crate::yield_now();
+            }
+            long.send("long").unwrap();
+        });
+        pool.execute(move || tx.send("short").unwrap());
+
+        let resumed = Instant::now();
+        pool.resume();
+
+        // The long job takes half a second; the short one only has to wait
+        // for its first chunk.
+        assert_eq!(rx.recv().unwrap(), "short");
+        assert!(resumed.elapsed() < Duration::from_millis(250));
+        assert_eq!(rx.recv().unwrap(), "long");
+    });
+}
+
+#[test]
+fn a_job_run_by_yield_now_cannot_yield_in_turn() {
+    let pool = ThreadPool::new(1);
+    let (tx, rx) = mpsc::channel();
+    pool.pause();
+
+    let outer = tx.clone();
+    pool.execute(move || outer.send(("outer", crate::yield_now())).unwrap());
+    let inner = tx.clone();
+    pool.execute(move || inner.send(("inner", crate::yield_now())).unwrap());
+    pool.execute(move || tx.send(("last", false)).unwrap());
+
+    pool.resume();
+    pool.join().unwrap();
+
+    // The inner job runs nested in the outer one and can't run the last
+    // one in turn, which is left for the worker.
+    let order: Vec<_> = rx.iter().collect();
+    assert_eq!(order, [("inner", false), ("outer", true), ("last", false)]);
+}
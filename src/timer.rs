@@ -0,0 +1,166 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    mem,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Condvar, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::Job;
+
+/// A handle to a job started with
+/// [`ThreadPool::execute_every`](crate::ThreadPool::execute_every).
+///
+/// Dropping the handle doesn't stop the job; call [`TaskHandle::cancel`] for
+/// that.
+#[derive(Debug, Clone)]
+pub struct TaskHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TaskHandle {
+    pub(crate) fn new() -> TaskHandle {
+        TaskHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stop the job from being scheduled again.
+    ///
+    /// This doesn't wait for a run that's already in progress; that one
+    /// finishes as normal, but no further runs start after it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, atomic::Ordering::Release);
+    }
+
+    /// Whether [`TaskHandle::cancel`] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(atomic::Ordering::Acquire)
+    }
+}
+
+// Holds jobs that aren't due yet. A single timer thread per pool sleeps until
+// the earliest one is due, then hands it over to the normal queue.
+pub(crate) struct Timer {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    // bumped for every entry, so jobs due at the same instant keep the order
+    // they were scheduled in
+    next_seq: u64,
+    closed: bool,
+}
+
+struct Entry {
+    due: Instant,
+    seq: u64,
+    action: Action,
+}
+
+// What to do with an entry once it comes due.
+pub(crate) enum Action {
+    // hand the job to a worker through the normal queue
+    Enqueue(Job),
+    // run it right there on the timer thread. Only for quick internal bits
+    // like flipping a flag, which mustn't wait behind a busy pool.
+    Inline(Job),
+}
+
+// `BinaryHeap` is a max-heap, so the ordering is reversed to have the
+// earliest entry come out on top.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            state: Mutex::new(State {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            wake: Condvar::new(),
+        }
+    }
+
+    // Hold on to `action` until `due`, or give it back if the timer has been
+    // closed.
+    pub(crate) fn schedule(&self, due: Instant, action: Action) -> Result<(), Action> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err(action);
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry { due, seq, action });
+
+        // The new entry might be due sooner than whatever the timer thread
+        // is currently sleeping towards.
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    // Block until the next entry is due and take it. Returns `None` once the
+    // timer is closed, at which point anything still waiting is dropped.
+    pub(crate) fn next_due(&self) -> Option<Action> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return None;
+            }
+
+            let now = Instant::now();
+
+            state = match state.entries.peek() {
+                None => self.wake.wait(state).unwrap(),
+                Some(entry) if entry.due <= now => {
+                    return state.entries.pop().map(|entry| entry.action);
+                }
+                Some(entry) => {
+                    let timeout = entry.due - now;
+                    self.wake.wait_timeout(state, timeout).unwrap().0
+                }
+            };
+        }
+    }
+
+    // Stop taking new jobs, throw away the ones that haven't come due and
+    // wake the timer thread so it exits.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let entries = mem::take(&mut state.entries);
+        self.wake.notify_all();
+        drop(state);
+
+        // Dropping a job can run arbitrary code, which might well try to
+        // schedule something, so leave the lock first.
+        drop(entries);
+    }
+}
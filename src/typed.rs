@@ -0,0 +1,226 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use crate::{panic_message, PoolCreationError, DEFAULT_THREAD_NAME_PREFIX};
+
+/// A bare-bones pool for running lots of jobs that are all the same type.
+///
+/// [`ThreadPool`](crate::ThreadPool) boxes every job so that it can take
+/// any closure at all, which costs an allocation per job and a dynamic
+/// call to run it. When every job is the same type `F`, say one closure
+/// built in a loop, this pool stores them as they are instead, and the
+/// workers call them directly.
+///
+/// That's all it does: there's no priority, delayed jobs, resizing or
+/// statistics. Jobs are run in the order they're handed over, a panicking
+/// job is logged and doesn't take its worker down, and dropping the pool
+/// waits for every job to finish, the same as a `ThreadPool`.
+///
+/// ```
+/// use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+/// use rustchat::TypedThreadPool;
+///
+/// let total = Arc::new(AtomicUsize::new(0));
+/// let pool = TypedThreadPool::new(4);
+///
+/// for i in 0..100 {
+///     let total = Arc::clone(&total);
+///     pool.execute(move || {
+///         total.fetch_add(i, Ordering::Relaxed);
+///     });
+/// }
+///
+/// drop(pool);
+/// assert_eq!(total.load(Ordering::Relaxed), 4950);
+/// ```
+pub struct TypedThreadPool<F> {
+    shared: Arc<Shared<F>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared<F> {
+    state: Mutex<State<F>>,
+    available: Condvar,
+}
+
+struct State<F> {
+    jobs: VecDeque<F>,
+    closed: bool,
+}
+
+impl<F> TypedThreadPool<F>
+where
+    F: FnOnce() + Send + 'static,
+{
+    /// Create a pool with `size` worker threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero, or if a worker thread could not be spawned.
+    pub fn new(size: usize) -> TypedThreadPool<F> {
+        TypedThreadPool::build(size).unwrap()
+    }
+
+    /// Create a pool with `size` worker threads, returning an error instead
+    /// of panicking.
+    ///
+    /// A size of zero gives back `PoolCreationError::ZeroSize`, and a
+    /// failure to spawn any of the worker threads is handed back as
+    /// `PoolCreationError::Spawn`.
+    pub fn build(size: usize) -> Result<TypedThreadPool<F>, PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        let mut pool = TypedThreadPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    jobs: VecDeque::new(),
+                    closed: false,
+                }),
+                available: Condvar::new(),
+            }),
+            workers: Vec::with_capacity(size),
+        };
+
+        // If one of them fails to spawn, dropping `pool` on the way out
+        // shuts down the workers we already made.
+        for id in 0..size {
+            let shared = Arc::clone(&pool.shared);
+            let builder =
+                thread::Builder::new().name(format!("{DEFAULT_THREAD_NAME_PREFIX}-typed-{id}"));
+            let worker = builder
+                .spawn(move || shared.run(id))
+                .map_err(PoolCreationError::Spawn)?;
+
+            pool.workers.push(worker);
+        }
+
+        Ok(pool)
+    }
+
+    /// Hand a job to the pool.
+    pub fn execute(&self, f: F) {
+        self.shared.state.lock().unwrap().jobs.push_back(f);
+        self.shared.available.notify_one();
+    }
+
+    /// The number of worker threads in the pool.
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Whether the pool has no worker threads, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+}
+
+impl<F: FnOnce()> Shared<F> {
+    // A worker's loop: take jobs until the queue is closed and empty.
+    fn run(&self, id: usize) {
+        loop {
+            let job = {
+                let mut state = self.state.lock().unwrap();
+
+                loop {
+                    if let Some(job) = state.jobs.pop_front() {
+                        break job;
+                    }
+
+                    if state.closed {
+                        return;
+                    }
+
+                    state = self.available.wait(state).unwrap();
+                }
+            };
+
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                warn!(
+                    "Typed worker {id} job panicked: {}",
+                    panic_message(payload.as_ref())
+                );
+            }
+        }
+    }
+}
+
+// Close the queue and wait for the workers, who finish whatever's left in it
+// first.
+impl<F> Drop for TypedThreadPool<F> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.available.notify_all();
+
+        for (id, worker) in self.workers.drain(..).enumerate() {
+            info!("Shutting down typed worker {id}");
+
+            if let Err(payload) = worker.join() {
+                warn!(
+                    "Typed worker {id} panicked: {}",
+                    panic_message(payload.as_ref())
+                );
+            }
+        }
+    }
+}
+
+// The jobs can't be printed, so this just gives the size.
+impl<F> fmt::Debug for TypedThreadPool<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedThreadPool")
+            .field("workers", &self.workers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::TypedThreadPool;
+
+    // A job written out as a struct, the way a caller might when the work
+    // needs more state than a closure comfortably holds.
+    struct Tally {
+        total: Arc<AtomicUsize>,
+        amount: usize,
+    }
+
+    impl Tally {
+        fn call(self) {
+            self.total.fetch_add(self.amount, Ordering::Relaxed);
+        }
+    }
+
+    // Every job built here is the same closure type, so they can all go
+    // through one typed pool.
+    fn job(tally: Tally) -> impl FnOnce() + Send + 'static {
+        move || tally.call()
+    }
+
+    #[test]
+    fn struct_jobs_all_run() {
+        let total = Arc::new(AtomicUsize::new(0));
+        let pool = TypedThreadPool::new(4);
+
+        for amount in 1..=1000 {
+            pool.execute(job(Tally {
+                total: Arc::clone(&total),
+                amount,
+            }));
+        }
+
+        drop(pool);
+        assert_eq!(total.load(Ordering::Relaxed), 500_500);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Waits for a group of jobs to finish.
+///
+/// Take a [`WaitGroupGuard`] with [`WaitGroup::enter`] for each job before
+/// handing it to the pool, and move the guard into the job. The job counts
+/// as done when the guard is dropped, which happens even if the job panics
+/// or is thrown away without running, so [`WaitGroup::wait`] can't be left
+/// waiting forever. Clones share the same count.
+///
+/// ```
+/// use rustchat::{ThreadPool, WaitGroup};
+///
+/// let pool = ThreadPool::new(4);
+/// let group = WaitGroup::new();
+///
+/// for _ in 0..8 {
+///     let guard = group.enter();
+///     pool.execute(move || {
+///         let _guard = guard;
+///         // ...
+///     });
+/// }
+///
+/// group.wait();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WaitGroup {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    pending: Mutex<usize>,
+    done: Condvar,
+}
+
+/// Counts one job as done, for the [`WaitGroup`] it came from, when it's
+/// dropped.
+#[derive(Debug)]
+#[must_use = "the job counts as done as soon as the guard is dropped"]
+pub struct WaitGroupGuard {
+    group: WaitGroup,
+}
+
+impl WaitGroup {
+    /// A group with nothing to wait for.
+    pub fn new() -> WaitGroup {
+        WaitGroup::default()
+    }
+
+    /// Add `n` jobs to the group. Each one needs a matching
+    /// [`WaitGroup::done`].
+    pub fn add(&self, n: usize) {
+        *self.inner.pending.lock().unwrap() += n;
+    }
+
+    /// Count one job as done.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more calls to `done` than jobs added.
+    pub fn done(&self) {
+        let mut pending = self.inner.pending.lock().unwrap();
+        *pending = pending
+            .checked_sub(1)
+            .expect("WaitGroup::done called more times than jobs were added");
+
+        if *pending == 0 {
+            self.inner.done.notify_all();
+        }
+    }
+
+    /// Add one job to the group, and get a guard that counts it as done
+    /// when it's dropped.
+    pub fn enter(&self) -> WaitGroupGuard {
+        self.add(1);
+
+        WaitGroupGuard {
+            group: self.clone(),
+        }
+    }
+
+    /// Block until every job in the group is done. Returns straight away
+    /// if there aren't any.
+    pub fn wait(&self) {
+        let mut pending = self.inner.pending.lock().unwrap();
+
+        while *pending > 0 {
+            pending = self.inner.done.wait(pending).unwrap();
+        }
+    }
+}
+
+impl Drop for WaitGroupGuard {
+    fn drop(&mut self) {
+        self.group.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::WaitGroup;
+    use crate::ThreadPool;
+
+    #[test]
+    fn wait_returns_only_once_every_job_is_done() {
+        let pool = ThreadPool::new(4);
+        let group = WaitGroup::new();
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..20 {
+            let guard = group.enter();
+            let finished = Arc::clone(&finished);
+            pool.execute(move || {
+                let _guard = guard;
+                thread::sleep(Duration::from_millis(10));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        group.wait();
+        assert_eq!(finished.load(Ordering::SeqCst), 20);
+    }
+}